@@ -1,48 +1,370 @@
+use std::alloc::{self, Layout};
+use std::borrow::Borrow;
 use std::cell::UnsafeCell;
-use std::mem::ManuallyDrop;
-use std::sync::atomic::{fence, Ordering::*};
-use std::usize;
-use std::{ops::Deref, ptr::NonNull, sync::atomic::AtomicUsize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicU32, Ordering::*};
+use std::task::{RawWaker, RawWakerVTable, Waker};
+use std::thread;
+use std::{ptr::NonNull, sync::atomic::AtomicUsize};
 
-struct ArcData<T> {
+use atomic_wait::{wait, wake_all};
+
+use crate::lock::Mutex;
+
+#[cfg(feature = "leak-tracker")]
+pub mod leak_tracker;
+pub mod rc;
+#[cfg(feature = "drop-hooks")]
+pub mod reclaimer;
+
+/// `Arc` が ArcData の確保と解放に使うアロケータ。
+/// (`std::alloc::Allocator` は unstable なので、自前のトレイトを用意する)
+///
+/// # Safety
+///
+/// `allocate` が返すメモリは `layout` を満たし、同じアロケータで `deallocate` されるまで有効でなければならない。
+pub unsafe trait Allocator {
+    /// 確保できなければ None を返す
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// # Safety
+    ///
+    /// `ptr` はこのアロケータの `allocate` に同じ `layout` を渡して得たものでなければならない。
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// グローバルアロケータ。`Arc` のデフォルト
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        if layout.size() == 0 {
+            // サイズ 0 の確保はグローバルアロケータに渡せない
+            return Some(NonNull::new(ptr::without_provenance_mut(layout.align())).unwrap());
+        }
+        NonNull::new(unsafe { alloc::alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            alloc::dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+// アリーナなどを参照で渡せるようにする
+unsafe impl<A: Allocator + ?Sized> Allocator for &A {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+}
+
+/// 参照カウントの上限。インクリメントでこれを超えたらオーバーフローとして扱う。
+/// 超えてから処理するまでの間に他のスレッドがインクリメントしても、usize::MAX (get_mut のロック) には届かない。
+const MAX_REFCOUNT: usize = usize::MAX / 2;
+
+/// overflow-saturate で、あふれたカウントを固定する値。
+/// 上限と usize::MAX の中間なので、固定した後に多少増減しても、どちらにも届かない。
+#[cfg(all(feature = "overflow-saturate", not(feature = "overflow-panic")))]
+const SATURATED: usize = MAX_REFCOUNT + MAX_REFCOUNT / 2;
+
+/// `Arc::try_clone` などで、参照カウントが上限を超えるときのエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowError;
+
+impl fmt::Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("reference count overflow")
+    }
+}
+
+impl std::error::Error for OverflowError {}
+
+fn increment_ref_count(count: &AtomicUsize) {
+    if count.fetch_add(1, Relaxed) > MAX_REFCOUNT {
+        ref_count_overflow(count, true);
+    }
+}
+
+/// Release でデクリメントし、デクリメント前の値を返す
+fn decrement_ref_count(count: &AtomicUsize) -> usize {
+    let n = count.fetch_sub(1, Release);
+    #[cfg(all(feature = "overflow-saturate", not(feature = "overflow-panic")))]
+    if n > MAX_REFCOUNT {
+        // 固定したカウントは減らさないので、ゼロになることはない
+        count.store(SATURATED, Relaxed);
+    }
+    n
+}
+
+/// 参照カウントが上限を超えたときに、フィーチャで選んだ方法で処理する。
+/// `incremented` なら、すでに行ったインクリメントを取り消す必要がある。
+///
+/// - デフォルト:プロセスをアボートする
+/// - overflow-panic:インクリメントを取り消してパニックする
+/// - overflow-saturate:カウントを固定して戻る。データは解放されずにリークする
+///
+/// 両方のフィーチャが有効なら overflow-panic を優先する。
+#[cold]
+fn ref_count_overflow(count: &AtomicUsize, incremented: bool) {
+    #[cfg(feature = "overflow-panic")]
+    {
+        if incremented {
+            count.fetch_sub(1, Relaxed);
+        }
+        panic!("reference count overflow");
+    }
+    #[cfg(all(feature = "overflow-saturate", not(feature = "overflow-panic")))]
+    {
+        // 固定するので、インクリメントを取り消す必要はない
+        let _ = incremented;
+        count.store(SATURATED, Relaxed);
+    }
+    #[cfg(not(any(feature = "overflow-panic", feature = "overflow-saturate")))]
+    {
+        let _ = (count, incremented);
+        std::process::abort();
+    }
+}
+
+// スライスなどのアンサイズ型のために自分でレイアウトを計算するので、フィールドの並びを固定する
+#[repr(C)]
+struct ArcData<T: ?Sized, A: Allocator = Global> {
     /// `Arc` の数
     data_ref_count: AtomicUsize,
     /// `Weak` の数。`Arc` が 1 つでもあればさらに 1 足す
     alloc_ref_count: AtomicUsize,
+    /// data_ref_count がゼロになったときに呼ぶフックのリスト。ほとんどの Arc では null。
+    /// 使わない Arc にとっては無駄な 1 ワードなので、`drop-hooks` フィーチャが有効なときだけ持つ。
+    #[cfg(feature = "drop-hooks")]
+    hooks: AtomicPtr<Hook>,
+    /// この ArcData を確保したアロケータ。alloc_ref_count がゼロになったら、これで解放する
+    alloc: A,
     /// データ本体。weak ポインタしかなくなったらドロップされる
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
-pub struct Arc<T> {
-    ptr: NonNull<ArcData<T>>,
+/// `Arc::on_last_drop` と `Arc::new_deferred` で登録されたフック
+#[cfg(feature = "drop-hooks")]
+struct Hook {
+    kind: HookKind,
+    /// 先に登録されたフック
+    next: *mut Hook,
+}
+
+#[cfg(feature = "drop-hooks")]
+enum HookKind {
+    /// 最後の Arc がなくなったときに呼ぶコールバック
+    Callback(Box<dyn FnOnce() + Send>),
+    /// データのドロップと ArcData の解放を回収スレッドで行う関数
+    Deferred(unsafe fn(NonNull<()>)),
+}
+
+pub struct Arc<T: ?Sized, A: Allocator = Global> {
+    ptr: NonNull<ArcData<T, A>>,
 }
 
-unsafe impl<T: Send + Sync> Send for Arc<T> {}
-unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+// アロケータは ArcData と一緒に共有され、最後の Weak をドロップしたスレッドで解放に使われる
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Send for Arc<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Sync for Arc<T, A> {}
 
-pub struct Weak<T> {
-    ptr: NonNull<ArcData<T>>,
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
+    ptr: NonNull<ArcData<T, A>>,
 }
 
-unsafe impl<T: Send + Sync> Send for Weak<T> {}
-unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Send for Weak<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Sync for Weak<T, A> {}
 
 impl<T> Arc<T> {
     pub fn new(data: T) -> Arc<T> {
+        Arc::new_in(data, Global)
+    }
+
+    pub fn pin(data: T) -> Pin<Arc<T>> {
+        // 安全性:データは ArcData の中に置かれ、最後の Arc がドロップされるまで移動も解放もされない。
+        // データを移動しうる try_unwrap や make_mut は Arc そのものを要求するが、
+        // T が Unpin でない限り Pin<Arc<T>> から Arc を取り出すことはできない。
+        unsafe { Pin::new_unchecked(Arc::new(data)) }
+    }
+
+    /// 値を書き込む前の `Arc` を作る。値はスタックを経由せず、直接ヒープに書き込める。
+    pub fn new_uninit() -> Arc<MaybeUninit<T>> {
         Arc {
-            ptr: NonNull::from(Box::leak(Box::new(ArcData {
-                alloc_ref_count: AtomicUsize::new(1),
-                data_ref_count: AtomicUsize::new(1),
-                data: UnsafeCell::new(ManuallyDrop::new(data)),
-            }))),
+            ptr: unsafe { Arc::allocate_for_layout(Layout::new::<T>(), Global, |mem| mem.cast()) },
         }
     }
 
-    fn data(&self) -> &ArcData<T> {
+    /// データ部分をゼロで埋めた `Arc` を作る
+    pub fn new_zeroed() -> Arc<MaybeUninit<T>> {
+        let arc = Self::new_uninit();
+        unsafe { ptr::write_bytes(Arc::as_ptr(&arc).cast_mut(), 0, 1) };
+        arc
+    }
+
+    pub fn new_cyclic(data_fn: impl FnOnce(&Weak<T>) -> T) -> Arc<T> {
+        // data_ref_count をゼロにしておくことで、構築中は weak ポインタをアップグレードできない
+        let ptr = NonNull::from(Box::leak(Box::new(ArcData {
+            alloc_ref_count: AtomicUsize::new(1),
+            data_ref_count: AtomicUsize::new(0),
+            #[cfg(feature = "drop-hooks")]
+            hooks: AtomicPtr::new(ptr::null_mut()),
+            alloc: Global,
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+        })));
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::register::<T>(ptr.as_ptr() as *const ());
+        // MaybeUninit<T> と T のレイアウトは同じ
+        let weak = Weak {
+            ptr: ptr.cast::<ArcData<T>>(),
+        };
+        // data_fn がパニックした場合は weak のドロップによって解放される
+        let data = data_fn(&weak);
+        // 安全性:data_ref_count はゼロなので、データにアクセスする者は他にいない
+        unsafe { (*(*ptr.as_ptr()).data.get()).write(data) };
+        // Release は Weak::upgrade の Acquire に対応。
+        // アップグレードしたスレッドから、書き込んだデータが見えるようにするため。
+        unsafe { ptr.as_ref() }.data_ref_count.store(1, Release);
+        // weak が持っていた alloc_ref_count の 1 は、Arc を代表する暗黙の weak ポインタとして引き継ぐ
+        Arc {
+            ptr: ManuallyDrop::new(weak).ptr,
+        }
+    }
+}
+
+#[cfg(feature = "drop-hooks")]
+impl<T: Send + 'static> Arc<T> {
+    /// 最後の Arc がなくなったときに、データをその場でドロップせず、回収スレッドでドロップする `Arc` を作る。
+    /// 大きな値のドロップで、最後の Arc を手放したスレッドが止まらないようにするため。
+    pub fn new_deferred(data: T) -> Arc<T> {
+        unsafe fn reclaim<T>(ptr: NonNull<()>) {
+            let ptr = ptr.cast::<ArcData<T>>();
+            ManuallyDrop::drop(&mut *ptr.as_ref().data.get());
+            drop(Weak { ptr });
+        }
+
+        let arc = Arc::new(data);
+        arc.push_hook(HookKind::Deferred(reclaim::<T>));
+        arc
+    }
+}
+
+impl<T, A: Allocator> Arc<T, A> {
+    pub fn new_in(data: T, alloc: A) -> Arc<T, A> {
+        unsafe {
+            let ptr = Self::allocate_for_layout(Layout::new::<T>(), alloc, |mem| mem.cast());
+            ptr::addr_of_mut!((*ptr.as_ptr()).data).write(UnsafeCell::new(ManuallyDrop::new(data)));
+            Arc { ptr }
+        }
+    }
+
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        if Self::is_unique(arc) {
+            // 他に Arc も Weak もないので、そのまま書き換えてよい
+        } else if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Acquire, Relaxed)
+            .is_err()
+        {
+            // 他にも Arc があるので、データを複製した新しい ArcData に付け替える。
+            // コールバックは元の ArcData に残すが、遅延ドロップの指定は引き継ぐ。
+            let data = T::clone(arc);
+            #[cfg(feature = "drop-hooks")]
+            let deferred = arc.data().deferred_reclaim();
+            *arc = Arc::new_in(data, arc.data().alloc.clone());
+            #[cfg(feature = "drop-hooks")]
+            if let Some(reclaim) = deferred {
+                arc.push_hook(HookKind::Deferred(reclaim));
+            }
+        } else {
+            // Arc は自分だけで、Weak だけが残っている。
+            // data_ref_count をゼロにしたので、もう weak ポインタはアップグレードできない。
+            // データを新しい ArcData にムーブし、残った Weak とは縁を切る。
+            // 安全性:data_ref_count はゼロなので、他の場所からアクセスすることはない。
+            let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            let alloc = arc.data().alloc.clone();
+            let old = std::mem::replace(arc, Arc::new_in(data, alloc));
+            // 値は生き続けるので、フックは発火させずに新しい ArcData に移す
+            #[cfg(feature = "drop-hooks")]
+            {
+                let hooks = old.data().hooks.swap(ptr::null_mut(), Relaxed);
+                arc.data().hooks.store(hooks, Relaxed);
+            }
+            // 古い Arc のデクリメントは済んでいるので、暗黙の weak ポインタだけをドロップする
+            drop(Weak {
+                ptr: ManuallyDrop::new(old).ptr,
+            });
+        }
+        // 安全性:ここでは arc が唯一の Arc で、Weak もアップグレードできない。
+        unsafe { &mut *arc.data().data.get() }
+    }
+
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        // Acquire は Arc::drop の Release デクリメントに対応。
+        // 他の Arc によるデータへのアクセスがすべて終わっていることを保証するため。
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Acquire, Relaxed)
+            .is_err()
+        {
+            return Err(arc);
+        }
+        let arc = ManuallyDrop::new(arc);
+        // 値は呼び出し元に渡すので、遅延ドロップの指定は無視する
+        #[cfg(feature = "drop-hooks")]
+        let _ = unsafe { arc.data().run_last_drop_hooks() };
+        // 安全性:data_ref_count をゼロにしたので、データにアクセスするのは自分だけ。
+        // weak ポインタも data_ref_count がゼロのためアップグレードできない。
+        let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+        // すべての `Arc<T>` を代表していた暗黙の weak ポインタをドロップする
+        drop(Weak { ptr: arc.ptr });
+        Ok(data)
+    }
+
+    pub fn into_inner(arc: Self) -> Option<T> {
+        // try_unwrap(arc).ok() だと、複数スレッドが同時に呼んだ場合に
+        // どのスレッドも値を取り出せずにドロップしてしまう可能性がある。
+        // Drop と同じくデクリメントしてしまえば、最後の 1 つだけが必ず値を受け取る。
+        let arc = ManuallyDrop::new(arc);
+        if decrement_ref_count(&arc.data().data_ref_count) != 1 {
+            return None;
+        }
+        fence(Acquire);
+        #[cfg(feature = "drop-hooks")]
+        let _ = unsafe { arc.data().run_last_drop_hooks() };
+        // 安全性:データへの参照カウントはゼロなので、
+        // 他の場所からアクセスすることはない。
+        let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+        drop(Weak { ptr: arc.ptr });
+        Some(data)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Arc<T, A> {
+    fn data(&self) -> &ArcData<T, A> {
         unsafe { self.ptr.as_ref() }
     }
 
-    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+    /// `arc` が唯一の `Arc` で、`Weak` も存在しなければ true
+    fn is_unique(arc: &mut Self) -> bool {
         // Acquire は Weak::drop の Release デクリメントと対応する。
         // アップグレードされた weak があれば、次の data_ref_count.load で観測できるようにするため。
         if arc
@@ -51,31 +373,84 @@ impl<T> Arc<T> {
             .compare_exchange(1, usize::MAX, Acquire, Relaxed)
             .is_err()
         {
-            return None;
+            return false;
         }
         let is_unique = arc.data().data_ref_count.load(Relaxed) == 1;
-        // Release は `downgrade` の Acquire インクリメントに対応する。
-        // `downgrade` 以降の data_ref_count への何らかの変更が、
-        // 上の is_unique の結果に影響しないようにするため。
-        arc.data().alloc_ref_count.store(1, Release);
+        Self::unlock_alloc_ref_count(arc);
         if !is_unique {
-            return None;
+            return false;
         }
         // Acquire は Arc::drop の Release デクリメントに対応。
         // 他の何もデータにアクセスしていないことを保証するため。
         fence(Acquire);
+        true
+    }
+
+    /// is_unique がかけた alloc_ref_count のロックを外し、待っている downgrade を起こす
+    fn unlock_alloc_ref_count(arc: &Self) {
+        // Release は `downgrade` の Acquire インクリメントに対応する。
+        // `downgrade` 以降の data_ref_count への何らかの変更が、
+        // 上の is_unique の結果に影響しないようにするため。
+        // SeqCst は downgrade_contended と対になり、待機を始めたスレッドを取りこぼさないため。
+        if arc.data().alloc_ref_count.swap(1, SeqCst) == LOCKED_WITH_WAITERS {
+            let waiters = downgrade_waiters(arc.ptr.as_ptr() as *const ());
+            waiters.fetch_add(1, SeqCst);
+            wake_all(waiters);
+        }
+    }
+
+    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+        if !Self::is_unique(arc) {
+            return None;
+        }
         unsafe { Some(&mut *arc.data().data.get()) }
     }
 
-    pub fn downgrade(arc: &Self) -> Weak<T> {
+    /// `clone` と同じだが、参照カウントが上限を超えるときはエラーを返す
+    pub fn try_clone(arc: &Self) -> Result<Self, OverflowError> {
+        let count = &arc.data().data_ref_count;
+        if count.fetch_add(1, Relaxed) > MAX_REFCOUNT {
+            count.fetch_sub(1, Relaxed);
+            return Err(OverflowError);
+        }
+        Ok(Arc { ptr: arc.ptr })
+    }
+
+    /// 最後の Arc がなくなったとき (`try_unwrap` や `into_inner` で値を取り出したときも含む) に、
+    /// 一度だけ `f` を呼ぶ。`f` は最後の Arc を手放したスレッドで、登録した順に呼ばれる。
+    #[cfg(feature = "drop-hooks")]
+    pub fn on_last_drop(arc: &Self, f: impl FnOnce() + Send + 'static) {
+        arc.push_hook(HookKind::Callback(Box::new(f)));
+    }
+
+    #[cfg(feature = "drop-hooks")]
+    fn push_hook(&self, kind: HookKind) {
+        let hooks = &self.data().hooks;
+        let hook = Box::into_raw(Box::new(Hook {
+            kind,
+            next: hooks.load(Relaxed),
+        }));
+        // Release は run_last_drop_hooks の Acquire に対応
+        while let Err(e) =
+            hooks.compare_exchange_weak(unsafe { (*hook).next }, hook, Release, Relaxed)
+        {
+            unsafe { (*hook).next = e };
+        }
+    }
+
+    pub fn downgrade(arc: &Self) -> Weak<T, A> {
         let mut n = arc.data().alloc_ref_count.load(Relaxed);
         loop {
-            if n == usize::MAX {
-                std::hint::spin_loop();
-                n = arc.data().alloc_ref_count.load(Relaxed);
+            if n == usize::MAX || n == LOCKED_WITH_WAITERS {
+                // get_mut がロックしているので、外れるまで待つ
+                n = Self::downgrade_contended(arc);
                 continue;
             }
-            assert!(n < usize::MAX - 1);
+            if n > MAX_REFCOUNT {
+                // overflow-saturate ならカウントは固定されたので、インクリメントせずに返す
+                ref_count_overflow(&arc.data().alloc_ref_count, false);
+                return Weak { ptr: arc.ptr };
+            }
             // Acquire は get_mut の Release ストアと同期
             if let Err(e) =
                 arc.data()
@@ -88,36 +463,429 @@ impl<T> Arc<T> {
             return Weak { ptr: arc.ptr };
         }
     }
+
+    /// get_mut が alloc_ref_count をロックしている間、少しだけスピンし、それでも外れなければスリープする。
+    /// ロックが外れた後の alloc_ref_count を返す。
+    #[cold]
+    fn downgrade_contended(arc: &Self) -> usize {
+        let count = &arc.data().alloc_ref_count;
+        let mut spin_count = 0;
+        let mut n = count.load(Relaxed);
+        while (n == usize::MAX || n == LOCKED_WITH_WAITERS) && spin_count < 100 {
+            spin_count += 1;
+            std::hint::spin_loop();
+            n = count.load(Relaxed);
+        }
+        if n != usize::MAX && n != LOCKED_WITH_WAITERS {
+            return n;
+        }
+        let waiters = downgrade_waiters(arc.ptr.as_ptr() as *const ());
+        loop {
+            // シーケンス番号を読んでから、ロックの状態を読み直す。
+            // 逆の順にすると、その間にロックが外れたときに、外れたロックを待ち続けてしまう。
+            // この順なら、ロックを外したスレッドがシーケンス番号を進めるので、wait はすぐに戻る。
+            let seq = waiters.load(SeqCst);
+            let n = count.load(SeqCst);
+            if n == usize::MAX {
+                if count
+                    .compare_exchange(usize::MAX, LOCKED_WITH_WAITERS, SeqCst, Relaxed)
+                    .is_err()
+                {
+                    continue;
+                }
+            } else if n != LOCKED_WITH_WAITERS {
+                return n;
+            }
+            // 同じバケットを使う他の ArcData のために起こされることもあるので、起きたら確認し直す
+            wait(waiters, seq);
+        }
+    }
+
+    pub fn strong_count(arc: &Self) -> usize {
+        // 他のスレッドがいつでも変更しうるので、得られるのはある時点での値でしかない
+        arc.data().data_ref_count.load(Relaxed)
+    }
+
+    /// `Weak` の数。すべての `Arc` を代表する暗黙の weak ポインタは数えない。
+    pub fn weak_count(arc: &Self) -> usize {
+        let n = arc.data().alloc_ref_count.load(Relaxed);
+        if n == usize::MAX || n == LOCKED_WITH_WAITERS {
+            // get_mut がロックしているのは、Weak がひとつもないときだけ
+            0
+        } else {
+            n - 1
+        }
+    }
+
+    /// 同じ ArcData を指していれば true (メタデータは比較しない)
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    /// `Arc<T>` を `Arc<dyn Trait>` などのアンサイズ型に変換する。
+    /// 通常は `unsize_arc!` マクロを使う。
+    ///
+    /// # Safety
+    ///
+    /// `coerce` は受け取ったポインタをアンサイズ強制して返すだけでなければならない。
+    pub unsafe fn unsize<U: ?Sized>(
+        arc: Self,
+        coerce: impl FnOnce(*const T) -> *const U,
+    ) -> Arc<U, A> {
+        // アドレスは変わらないので、そのままヘッダを辿れる
+        Arc::from_data_ptr(coerce(Arc::into_raw(arc)))
+    }
+
+    pub fn as_ptr(arc: &Self) -> *const T {
+        // 参照を経由すると data フィールドの外に触れられなくなるので、生ポインタから作る
+        unsafe { ptr::addr_of_mut!((*arc.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// `Arc` を消費してデータへのポインタを返す。参照カウントはそのまま残る。
+    pub fn into_raw(arc: Self) -> *const T {
+        Self::as_ptr(&ManuallyDrop::new(arc))
+    }
+
+    /// `into_raw` で得たポインタから `Arc` を復元する。
+    /// (呼び出し側でアロケータの型を推論できないので、公開するのは Global 用の from_raw だけにする)
+    unsafe fn from_data_ptr(ptr: *const T) -> Self {
+        Arc {
+            ptr: NonNull::new_unchecked(arcdata_from_data_ptr(ptr)),
+        }
+    }
+
+    /// 値のレイアウトが `value_layout` の ArcData を `alloc` で確保し、カウンタを初期化する。
+    /// `mem_to_arcdata` は確保したメモリを、(スライスなら長さなどの) メタデータ付きのポインタに変換する。
+    unsafe fn allocate_for_layout(
+        value_layout: Layout,
+        alloc: A,
+        mem_to_arcdata: impl FnOnce(*mut u8) -> *mut ArcData<T, A>,
+    ) -> NonNull<ArcData<T, A>> {
+        let layout = arcdata_layout::<A>(value_layout);
+        let Some(mem) = alloc.allocate(layout) else {
+            alloc::handle_alloc_error(layout);
+        };
+        let inner = mem_to_arcdata(mem.as_ptr());
+        ptr::addr_of_mut!((*inner).data_ref_count).write(AtomicUsize::new(1));
+        ptr::addr_of_mut!((*inner).alloc_ref_count).write(AtomicUsize::new(1));
+        #[cfg(feature = "drop-hooks")]
+        ptr::addr_of_mut!((*inner).hooks).write(AtomicPtr::new(ptr::null_mut()));
+        ptr::addr_of_mut!((*inner).alloc).write(alloc);
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::register::<T>(inner as *const ());
+        NonNull::new_unchecked(inner)
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    /// # Safety
+    ///
+    /// `ptr` は `Arc::into_raw` で得たもので、その分の参照カウントを引き継ぐ。
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        Arc::from_data_ptr(ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` は `Arc::into_raw` で得たもので、対応する `Arc` がまだ生きていなければならない。
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // 手元の Arc をドロップしないように ManuallyDrop で包んでから複製する
+        let arc = ManuallyDrop::new(Arc::from_raw(ptr));
+        mem::forget(Arc::clone(&arc));
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` は `Arc::into_raw` で得たもので、参照カウントが 1 つ以上残っていなければならない。
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(Arc::from_raw(ptr));
+    }
+}
+
+impl<T> Arc<[T]> {
+    pub fn new_uninit_slice(len: usize) -> Arc<[MaybeUninit<T>]> {
+        Arc {
+            ptr: unsafe { Arc::allocate_for_slice(len) },
+        }
+    }
+
+    pub fn new_zeroed_slice(len: usize) -> Arc<[MaybeUninit<T>]> {
+        let arc = Self::new_uninit_slice(len);
+        unsafe { ptr::write_bytes(Arc::as_ptr(&arc).cast_mut().cast::<T>(), 0, len) };
+        arc
+    }
+
+    /// 長さ `len` のスライス用に ArcData を確保する。要素は未初期化のまま。
+    unsafe fn allocate_for_slice(len: usize) -> NonNull<ArcData<[T]>> {
+        Self::allocate_for_layout(Layout::array::<T>(len).unwrap(), Global, |mem| {
+            ptr::slice_from_raw_parts_mut(mem.cast::<T>(), len) as *mut ArcData<[T]>
+        })
+    }
+}
+
+impl<T> Arc<MaybeUninit<T>> {
+    /// # Safety
+    ///
+    /// 値はすでに初期化されていなければならない。
+    pub unsafe fn assume_init(self) -> Arc<T> {
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::retype::<T>(self.ptr.as_ptr() as *const ());
+        // MaybeUninit<T> と T のレイアウトは同じ
+        Arc {
+            ptr: ManuallyDrop::new(self).ptr.cast(),
+        }
+    }
+}
+
+impl<T> Arc<[MaybeUninit<T>]> {
+    /// # Safety
+    ///
+    /// すべての要素がすでに初期化されていなければならない。
+    pub unsafe fn assume_init(self) -> Arc<[T]> {
+        let ptr = ManuallyDrop::new(self).ptr.as_ptr() as *mut ArcData<[T]>;
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::retype::<[T]>(ptr as *const ());
+        Arc {
+            ptr: NonNull::new_unchecked(ptr),
+        }
+    }
+}
+
+#[cfg(feature = "drop-hooks")]
+impl<T: ?Sized, A: Allocator> ArcData<T, A> {
+    /// 登録されたコールバックを登録順に呼び、遅延ドロップが指定されていればその関数を返す。
+    ///
+    /// # Safety
+    ///
+    /// data_ref_count がゼロになった後に、一度だけ呼べる。
+    unsafe fn run_last_drop_hooks(&self) -> Option<unsafe fn(NonNull<()>)> {
+        let mut hook = self.hooks.swap(ptr::null_mut(), Acquire);
+        if hook.is_null() {
+            return None;
+        }
+        let mut callbacks = Vec::new();
+        let mut deferred = None;
+        while !hook.is_null() {
+            let h = Box::from_raw(hook);
+            hook = h.next;
+            match h.kind {
+                HookKind::Callback(f) => callbacks.push(f),
+                HookKind::Deferred(reclaim) => deferred = Some(reclaim),
+            }
+        }
+        // リストは新しいものが先頭なので、逆順に呼ぶ
+        for f in callbacks.into_iter().rev() {
+            f();
+        }
+        deferred
+    }
+
+    /// 遅延ドロップが指定されていれば、その関数を返す
+    fn deferred_reclaim(&self) -> Option<unsafe fn(NonNull<()>)> {
+        // Arc を持っている間はフックが解放されることはない
+        let mut hook = self.hooks.load(Acquire);
+        while let Some(h) = unsafe { hook.as_ref() } {
+            if let HookKind::Deferred(reclaim) = h.kind {
+                return Some(reclaim);
+            }
+            hook = h.next;
+        }
+        None
+    }
+}
+
+/// ヘッダ (カウンタとアロケータ) の後ろに `value_layout` の値を置いた ArcData のレイアウト
+fn arcdata_layout<A: Allocator>(value_layout: Layout) -> Layout {
+    arcdata_header_layout::<A>()
+        .extend(value_layout)
+        .unwrap()
+        .0
+        .pad_to_align()
+}
+
+/// ArcData の先頭から data フィールドまでのバイト数
+fn data_offset<A: Allocator>(value_align: usize) -> usize {
+    let value_layout = Layout::from_size_align(0, value_align).unwrap();
+    arcdata_header_layout::<A>().extend(value_layout).unwrap().1
+}
+
+fn arcdata_header_layout<A: Allocator>() -> Layout {
+    // ArcData は repr(C) なので、data より前のフィールドの配置は T によらない。
+    // ArcData<()> のサイズは末尾のパディングを含んでしまうので、data のオフセットを使う。
+    Layout::from_size_align(
+        mem::offset_of!(ArcData<(), A>, data),
+        mem::align_of::<ArcData<(), A>>(),
+    )
+    .unwrap()
+}
+
+/// get_mut が alloc_ref_count をロックしている間に、downgrade が待機していることを示す値。
+/// (ロックしているだけなら usize::MAX)
+const LOCKED_WITH_WAITERS: usize = usize::MAX - 1;
+
+/// downgrade が get_mut のロックを待つための futex。
+/// ArcData ごとに用意すると大きくなるので、アドレスで選んだバケットを共有する。
+/// 値はロックを外すたびに進めるシーケンス番号。
+static DOWNGRADE_WAITERS: [AtomicU32; 64] = [const { AtomicU32::new(0) }; 64];
+
+fn downgrade_waiters(arcdata: *const ()) -> &'static AtomicU32 {
+    // ArcData のアラインメントより下のビットは常に同じなので使わない
+    &DOWNGRADE_WAITERS[(arcdata.addr() >> 4) % DOWNGRADE_WAITERS.len()]
+}
+
+/// `Weak::new` が使う番兵のアドレス。
+/// ArcData のアラインメントは 1 より大きいので、実際の ArcData がこのアドレスに置かれることはない。
+const DANGLING: usize = usize::MAX;
+
+fn is_dangling<T: ?Sized>(ptr: *const T) -> bool {
+    ptr.cast::<()>().addr() == DANGLING
+}
+
+/// データへのポインタから、それを含む ArcData へのポインタを求める
+unsafe fn arcdata_from_data_ptr<T: ?Sized, A: Allocator>(ptr: *const T) -> *mut ArcData<T, A> {
+    // データがドロップ済みでも、アラインメントは型とメタデータだけで決まる
+    let offset = data_offset::<A>(mem::align_of_val(&*ptr));
+    ptr.byte_sub(offset) as *mut ArcData<T, A>
+}
+
+/// 太いポインタのメタデータはそのままに、アドレスだけを `data` に差し替える
+unsafe fn set_data_ptr<T: ?Sized, U>(mut ptr: *mut T, data: *mut U) -> *mut T {
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, data as *mut u8);
+    ptr
+}
+
+/// `Arc<T>` を `Arc<dyn Trait>` や `Arc<[T]>` に変換する。
+/// (`CoerceUnsized` は unstable なので、ポインタのアンサイズ強制をマクロの中で行う)
+#[macro_export]
+macro_rules! unsize_arc {
+    ($arc:expr, $ty:ty) => {{
+        let arc = $arc;
+        // 安全性:クロージャはアンサイズ強制しかしないので、アドレスは変わらない
+        unsafe { $crate::arc::Arc::unsize(arc, |p| -> *const $ty { p }) }
+    }};
 }
 
 impl<T> Weak<T> {
-    fn data(&self) -> &ArcData<T> {
-        unsafe { self.ptr.as_ref() }
+    /// どの ArcData も指さない `Weak` を作る。アップグレードは常に失敗する。
+    pub const fn new() -> Weak<T> {
+        Weak {
+            // 安全性:usize::MAX は null ではない
+            ptr: unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(DANGLING)) },
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    /// `Weak::new` で作られたものなら None
+    fn data(&self) -> Option<&ArcData<T, A>> {
+        if is_dangling(self.ptr.as_ptr()) {
+            None
+        } else {
+            unsafe { Some(self.ptr.as_ref()) }
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.data()
+            .map_or(0, |data| data.data_ref_count.load(Relaxed))
     }
 
-    pub fn upgrade(&self) -> Option<Arc<T>> {
-        let mut n = self.data().data_ref_count.load(Relaxed);
+    /// 自分を含む `Weak` の数。`Arc` が残っていなければ 0 を返す。
+    pub fn weak_count(&self) -> usize {
+        let Some(data) = self.data() else {
+            return 0;
+        };
+        let weak = data.alloc_ref_count.load(Relaxed);
+        if data.data_ref_count.load(Relaxed) == 0 {
+            0
+        } else {
+            // Arc があるので、暗黙の weak ポインタの分を引く
+            weak - 1
+        }
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        ptr::addr_eq(self.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+
+    /// データへのポインタを返す。データがドロップ済みなら参照外しはできない。
+    pub fn as_ptr(&self) -> *const T {
+        if is_dangling(self.ptr.as_ptr()) {
+            // ArcData がないので、番兵のアドレスをそのまま返す
+            return self.ptr.as_ptr() as *const T;
+        }
+        unsafe { ptr::addr_of_mut!((*self.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// `Weak` を消費してデータへのポインタを返す。alloc_ref_count はそのまま残る。
+    pub fn into_raw(self) -> *const T {
+        ManuallyDrop::new(self).as_ptr()
+    }
+
+    pub fn upgrade(&self) -> Option<Arc<T, A>> {
+        let data = self.data()?;
+        match self.try_upgrade() {
+            Ok(arc) => arc,
+            Err(OverflowError) => {
+                // overflow-saturate ならカウントは固定されたので、インクリメントせずに返す
+                ref_count_overflow(&data.data_ref_count, false);
+                Some(Arc { ptr: self.ptr })
+            }
+        }
+    }
+
+    /// `upgrade` と同じだが、参照カウントが上限を超えるときはエラーを返す
+    pub fn try_upgrade(&self) -> Result<Option<Arc<T, A>>, OverflowError> {
+        let Some(data) = self.data() else {
+            return Ok(None);
+        };
+        let mut n = data.data_ref_count.load(Relaxed);
         loop {
             if n == 0 {
-                return None;
+                return Ok(None);
             }
-            assert!(n < usize::MAX);
-            if let Err(e) =
-                self.data()
-                    .data_ref_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+            if n > MAX_REFCOUNT {
+                return Err(OverflowError);
+            }
+            // Acquire は new_cyclic の Release ストアと同期。
+            // 構築中に渡された weak ポインタからも、書き込まれたデータが見えるようにするため。
+            if let Err(e) = data
+                .data_ref_count
+                .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 // 現在の値が n でなかった（カウンタが更新されていた）ので比較交換に失敗
                 // 次のループでリトライする
                 n = e;
                 continue;
             }
-            return Some(Arc { ptr: self.ptr });
+            return Ok(Some(Arc { ptr: self.ptr }));
         }
     }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized> Weak<T> {
+    /// # Safety
+    ///
+    /// `ptr` は `Weak::into_raw` で得たもので、その分の alloc_ref_count を引き継ぐ。
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let ptr = if is_dangling(ptr) {
+            ptr as *mut ArcData<T>
+        } else {
+            arcdata_from_data_ptr(ptr)
+        };
+        Weak {
+            ptr: NonNull::new_unchecked(ptr),
+        }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Weak::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Deref for Arc<T, A> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -127,28 +895,32 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized, A: Allocator> Clone for Arc<T, A> {
     fn clone(&self) -> Self {
-        if self.data().data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
-            std::process::abort();
-        }
+        increment_ref_count(&self.data().data_ref_count);
         Arc { ptr: self.ptr }
     }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized, A: Allocator> Clone for Weak<T, A> {
     fn clone(&self) -> Self {
-        if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
-            std::process::abort();
+        if let Some(data) = self.data() {
+            increment_ref_count(&data.alloc_ref_count);
         }
         Weak { ptr: self.ptr }
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized, A: Allocator> Drop for Arc<T, A> {
     fn drop(&mut self) {
-        if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
+        if decrement_ref_count(&self.data().data_ref_count) == 1 {
             fence(Acquire);
+            #[cfg(feature = "drop-hooks")]
+            if let Some(reclaim) = unsafe { self.data().run_last_drop_hooks() } {
+                // データのドロップと暗黙の weak ポインタのドロップは、回収スレッドに任せる
+                unsafe { reclaimer::defer(self.ptr.cast(), reclaim) };
+                return;
+            }
             // 安全性:データへの参照カウントはゼロなので、
             // 他の場所からアクセスすることはない。
             unsafe {
@@ -161,33 +933,531 @@ impl<T> Drop for Arc<T> {
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
+        let Some(data) = self.data() else {
+            return;
+        };
+        if decrement_ref_count(&data.alloc_ref_count) == 1 {
             fence(Acquire);
             unsafe {
-                drop(Box::from_raw(self.ptr.as_ptr()));
+                let layout = Layout::for_value(self.ptr.as_ref());
+                // アロケータ自身も ArcData の中にあるので、取り出してから解放する
+                let alloc = ptr::read(&self.ptr.as_ref().alloc);
+                // 解放した直後に同じアドレスが確保されることがあるので、記録は先に消す
+                #[cfg(feature = "leak-tracker")]
+                leak_tracker::unregister(self.ptr.as_ptr() as *const ());
+                alloc.deallocate(self.ptr.cast(), layout);
             }
         }
     }
 }
 
-#[test]
-fn test() {
-    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        unsafe {
+            let ptr = Arc::allocate_for_slice(v.len());
+            let data = ptr::addr_of_mut!((*ptr.as_ptr()).data) as *mut T;
+            ptr::copy_nonoverlapping(v.as_ptr(), data, v.len());
+            // 要素はムーブ済みなので、Vec にはバッファの解放だけをさせる
+            v.set_len(0);
+            Arc { ptr }
+        }
+    }
+}
 
-    struct DetectDrop;
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Arc::from(v.to_vec())
+    }
+}
 
-    impl Drop for DetectDrop {
-        fn drop(&mut self) {
-            NUM_DROPS.fetch_add(1, Relaxed);
+impl<T> FromIterator<T> for Arc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Arc::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(s: &str) -> Self {
+        let arc = ManuallyDrop::new(Arc::<[u8]>::from(s.as_bytes()));
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::retype::<str>(arc.ptr.as_ptr() as *const ());
+        // 安全性:中身は UTF-8 のバイト列で、[u8] と str のレイアウトは同じ
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(arc.ptr.as_ptr() as *mut ArcData<str>) },
         }
     }
+}
 
-    // この時点では weak ポインタはアップグレード可能
-    let x = Arc::new(("hello", DetectDrop));
-    let y = Arc::downgrade(&x);
-    let z = Arc::downgrade(&x);
+impl From<String> for Arc<str> {
+    fn from(s: String) -> Self {
+        Arc::from(&s[..])
+    }
+}
+
+impl<T: ?Sized> From<Box<T>> for Arc<T> {
+    fn from(b: Box<T>) -> Self {
+        let value_layout = Layout::for_value(&*b);
+        let src = Box::into_raw(b);
+        unsafe {
+            let ptr = Arc::allocate_for_layout(value_layout, Global, |mem| {
+                set_data_ptr(src as *mut ArcData<T>, mem)
+            });
+            let data = ptr::addr_of_mut!((*ptr.as_ptr()).data) as *mut u8;
+            ptr::copy_nonoverlapping(src as *const u8, data, value_layout.size());
+            // 値はムーブ済みなので、ドロップせずにメモリだけを解放する
+            drop(Box::from_raw(src as *mut ManuallyDrop<T>));
+            Arc { ptr }
+        }
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(data: T) -> Self {
+        Arc::new(data)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl Default for Arc<str> {
+    fn default() -> Self {
+        Arc::from("")
+    }
+}
+
+impl<T> Default for Arc<[T]> {
+    fn default() -> Self {
+        Arc::from(Vec::new())
+    }
+}
+
+// 比較やハッシュはポインタではなく、中身の値に委ねる
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Arc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Arc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Arc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Arc<T, A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, A: Allocator> Hash for Arc<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Borrow<T> for Arc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Arc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator> fmt::Display for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Pointer for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&Arc::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Debug for Weak<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 中身はドロップされているかもしれないので表示しない
+        write!(f, "(Weak)")
+    }
+}
+
+// ArcData を移動することはないので、Arc 自体はいつ移動してもよい
+impl<T: ?Sized, A: Allocator> Unpin for Arc<T, A> {}
+
+// UnsafeCell を含むため自動では実装されないが、Arc を通して得られるのは共有参照だけなので、
+// T が RefUnwindSafe であればパニックを跨いでも問題ない
+impl<T: ?Sized + RefUnwindSafe, A: Allocator + UnwindSafe> UnwindSafe for Arc<T, A> {}
+
+/// `Arc<W>` を `Waker` として使うためのトレイト。
+/// (`std::task::Wake` は std の `Arc` にしか使えないので、自前で用意する)
+pub trait Wake {
+    fn wake(this: Arc<Self>);
+
+    fn wake_by_ref(this: &Arc<Self>) {
+        Self::wake(this.clone());
+    }
+}
+
+impl<W: Wake + Send + Sync + 'static> From<Arc<W>> for Waker {
+    fn from(waker: Arc<W>) -> Waker {
+        // 安全性:vtable の各関数は、into_raw で得たポインタと参照カウントを正しく扱う
+        unsafe { Waker::from_raw(raw_waker(waker)) }
+    }
+}
+
+fn raw_waker<W: Wake + Send + Sync + 'static>(waker: Arc<W>) -> RawWaker {
+    // RawWaker のデータは、参照カウントを 1 つ持った Arc::into_raw のポインタ
+
+    unsafe fn clone_waker<W: Wake + Send + Sync + 'static>(data: *const ()) -> RawWaker {
+        Arc::increment_strong_count(data as *const W);
+        RawWaker::new(data, waker_vtable::<W>())
+    }
+
+    unsafe fn wake<W: Wake + Send + Sync + 'static>(data: *const ()) {
+        W::wake(Arc::from_raw(data as *const W));
+    }
+
+    unsafe fn wake_by_ref<W: Wake + Send + Sync + 'static>(data: *const ()) {
+        let waker = ManuallyDrop::new(Arc::from_raw(data as *const W));
+        W::wake_by_ref(&waker);
+    }
+
+    unsafe fn drop_waker<W: Wake + Send + Sync + 'static>(data: *const ()) {
+        Arc::decrement_strong_count(data as *const W);
+    }
+
+    fn waker_vtable<W: Wake + Send + Sync + 'static>() -> &'static RawWakerVTable {
+        &RawWakerVTable::new(
+            clone_waker::<W>,
+            wake::<W>,
+            wake_by_ref::<W>,
+            drop_waker::<W>,
+        )
+    }
+
+    RawWaker::new(Arc::into_raw(waker) as *const (), waker_vtable::<W>())
+}
+
+/// 共有する前の、唯一の所有者であることが保証された `Arc`。
+/// data_ref_count はゼロのままなので、ここから作った `Weak` は `into_arc` するまでアップグレードできない。
+pub struct UniqueArc<T: ?Sized> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for UniqueArc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for UniqueArc<T> {}
+
+impl<T> UniqueArc<T> {
+    pub fn new(data: T) -> UniqueArc<T> {
+        let ptr = NonNull::from(Box::leak(Box::new(ArcData {
+            // UniqueArc が Arc の代わりに暗黙の weak ポインタを持つ
+            alloc_ref_count: AtomicUsize::new(1),
+            data_ref_count: AtomicUsize::new(0),
+            #[cfg(feature = "drop-hooks")]
+            hooks: AtomicPtr::new(ptr::null_mut()),
+            alloc: Global,
+            data: UnsafeCell::new(ManuallyDrop::new(data)),
+        })));
+        #[cfg(feature = "leak-tracker")]
+        leak_tracker::register::<T>(ptr.as_ptr() as *const ());
+        UniqueArc { ptr }
+    }
+}
+
+impl<T: ?Sized> UniqueArc<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        // Arc がないので get_mut にロックされることはない
+        increment_ref_count(&this.data().alloc_ref_count);
+        Weak { ptr: this.ptr }
+    }
+
+    /// data_ref_count を 0 から 1 にするだけで `Arc` に変換する
+    pub fn into_arc(this: Self) -> Arc<T> {
+        let this = ManuallyDrop::new(this);
+        // Release は Weak::upgrade の Acquire に対応。
+        // UniqueArc を通して書き込んだデータが、アップグレードしたスレッドから見えるようにするため。
+        this.data().data_ref_count.store(1, Release);
+        // 暗黙の weak ポインタは、そのまま Arc が引き継ぐ
+        Arc { ptr: this.ptr }
+    }
+}
+
+impl<T: ?Sized> Deref for UniqueArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for UniqueArc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // 安全性:data_ref_count はゼロなので、weak ポインタからデータにアクセスされることはない
+        unsafe { &mut *self.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for UniqueArc<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut *self.data().data.get());
+        }
+        drop(Weak { ptr: self.ptr });
+    }
+}
+
+/// `Arc<T>` をアトミックに差し替えられるスロット。
+/// リーダはロックを取らずに、その時点の `Arc<T>` を受け取れる。
+///
+/// 古い値を解放してよいかは、load の「readers のインクリメント → ptr のロード」と、
+/// swap の「ptr の差し替え → readers のロード」の 2 組で決まる。
+/// 両方の間に SeqCst フェンスを置くことで、ライタがインクリメントを見逃したリーダは、
+/// 必ず差し替え後の ptr を読む (いわゆるストアバッファリングのパターン)。
+pub struct AtomicArc<T> {
+    ptr: AtomicPtr<ArcData<T>>,
+    /// リーダが使う readers のインデックス (0 か 1)
+    generation: AtomicUsize,
+    /// ptr を読んでから data_ref_count をインクリメントし終えるまでの間にいるリーダの数
+    readers: [AtomicUsize; 2],
+    /// ライタ同士を直列化する
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for AtomicArc<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicArc<T> {}
+
+impl<T> AtomicArc<T> {
+    pub fn new(arc: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(ManuallyDrop::new(arc).ptr.as_ptr()),
+            generation: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        let g = self.generation.load(SeqCst);
+        // ptr を読む前に自分を数えておくことで、
+        // ライタが readers[g] のゼロを観測するまでは、読んだ ArcData が解放されないようにする
+        self.readers[g].fetch_add(1, SeqCst);
+        // wait_for_readers のフェンスと対になる。
+        // ライタが readers[g] のインクリメントを見逃したなら、こちらは swap 後の ptr を必ず読む。
+        fence(SeqCst);
+        let ptr = self.ptr.load(SeqCst);
+        // 安全性:スロットが持っている Arc はまだドロップされていない。
+        // ManuallyDrop で包んで、スロットの分の参照カウントを減らさないようにする
+        let slot = ManuallyDrop::new(Arc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+        });
+        let arc = Arc::clone(&slot);
+        // Release は wait_for_readers の SeqCst ロードに対応。
+        // ライタが古い Arc をドロップする前に、上のインクリメントが見えるようにするため。
+        self.readers[g].fetch_sub(1, Release);
+        arc
+    }
+
+    pub fn store(&self, new: Arc<T>) {
+        drop(self.swap(new));
+    }
+
+    pub fn swap(&self, new: Arc<T>) -> Arc<T> {
+        let _guard = self.writer.lock();
+        self.swap_locked(new)
+    }
+
+    /// 現在の値が `current` と同じ ArcData なら `new` に差し替える。
+    /// いずれの場合も直前の値を返すので、`Arc::ptr_eq(&prev, current)` で成否がわかる。
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Arc<T> {
+        let _guard = self.writer.lock();
+        // ライタは自分だけなので、ロック中に ptr が変わることはない
+        if self.ptr.load(Relaxed) == current.ptr.as_ptr() {
+            self.swap_locked(new)
+        } else {
+            self.load()
+        }
+    }
+
+    /// 現在の値から `f` で新しい値を作って差し替え、古い値を返す。
+    /// `f` を呼んでいる間も他のライタを止めておくので、同時に呼ばれても更新は失われない。
+    pub fn update(&self, f: impl FnOnce(&T) -> T) -> Arc<T> {
+        let _guard = self.writer.lock();
+        let new = f(&self.load());
+        self.swap_locked(Arc::new(new))
+    }
+
+    fn swap_locked(&self, new: Arc<T>) -> Arc<T> {
+        let old = self.ptr.swap(ManuallyDrop::new(new).ptr.as_ptr(), SeqCst);
+        self.wait_for_readers();
+        // スロットが持っていた参照カウントを、そのまま呼び出し元に渡す
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(old) },
+        }
+    }
+
+    /// swap より前に古いポインタを読んだリーダが、すべてインクリメントを終えるまで待つ
+    fn wait_for_readers(&self) {
+        // 両方の readers がそれぞれ一度ゼロになるのを確認する。
+        // 待つ前に世代を切り替えておけば、新しいリーダは反対側を使うので、
+        // 待っている側の readers は (切り替え前に世代を読んだリーダが抜ければ) 必ずゼロになる。
+        for _ in 0..2 {
+            let g = self.generation.fetch_xor(1, SeqCst);
+            // swap と readers のロードの間に入れて、load のフェンスと対にする
+            fence(SeqCst);
+            let mut spin_count = 0;
+            while self.readers[g].load(SeqCst) != 0 {
+                if spin_count < 100 {
+                    spin_count += 1;
+                    std::hint::spin_loop();
+                } else {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        drop(Arc {
+            ptr: unsafe { NonNull::new_unchecked(*self.ptr.get_mut()) },
+        });
+    }
+}
+
+/// 自分の中に参照カウンタを持つオブジェクト (C ライブラリのオブジェクトなど)。
+/// `IntrusiveArc` は参照を増減するときにこれを呼ぶ。
+/// C ライブラリの `foo_ref()`/`foo_unref()` をそのまま呼んでもよいし、
+/// `AtomicUsize` のカウンタを持っているなら `inc_ref_count`/`dec_ref_count` を使って実装できる。
+///
+/// # Safety
+///
+/// 参照が 1 つでも残っている間、オブジェクトは移動も解放もされてはならない。
+pub unsafe trait RefCounted {
+    /// 参照を 1 つ増やす
+    fn inc_ref(&self);
+
+    /// 参照を 1 つ手放し、最後の参照だったらオブジェクトを解放する
+    ///
+    /// # Safety
+    ///
+    /// 呼び出し元は参照を 1 つ持っていなければならず、呼び出した後は `this` を使ってはならない。
+    unsafe fn dec_ref(this: NonNull<Self>);
+}
+
+/// `RefCounted::inc_ref` を `AtomicUsize` のカウンタで実装するためのもの。
+/// あふれたときは `Arc` と同じく、フィーチャで選んだ方法で処理する。
+pub fn inc_ref_count(count: &AtomicUsize) {
+    increment_ref_count(count);
+}
+
+/// `RefCounted::dec_ref` を `AtomicUsize` のカウンタで実装するためのもの。
+/// 最後の参照だったら true を返すので、そのときは呼び出し元がオブジェクトを解放する。
+pub fn dec_ref_count(count: &AtomicUsize) -> bool {
+    if decrement_ref_count(count) != 1 {
+        return false;
+    }
+    // Arc::drop と同じく、他のスレッドでのアクセスがすべて終わってから解放する
+    fence(Acquire);
+    true
+}
+
+/// オブジェクト自身の参照カウンタを使う `Arc`
+pub struct IntrusiveArc<T: RefCounted + ?Sized> {
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: RefCounted + ?Sized + Send + Sync> Send for IntrusiveArc<T> {}
+unsafe impl<T: RefCounted + ?Sized + Send + Sync> Sync for IntrusiveArc<T> {}
+
+impl<T: RefCounted + ?Sized> IntrusiveArc<T> {
+    /// 参照を 1 つ引き取る。カウンタは変更しない。
+    ///
+    /// # Safety
+    ///
+    /// `ptr` は生きているオブジェクトを指し、呼び出し元はその参照を 1 つ持っていなければならない。
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        IntrusiveArc {
+            ptr: NonNull::new_unchecked(ptr.cast_mut()),
+        }
+    }
+
+    /// 参照を 1 つ持ったまま、ポインタを返す。カウンタは変更しない。
+    pub fn into_raw(this: Self) -> *const T {
+        ManuallyDrop::new(this).ptr.as_ptr()
+    }
+
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+}
+
+impl<T: RefCounted + ?Sized> Deref for IntrusiveArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: RefCounted + ?Sized> Clone for IntrusiveArc<T> {
+    fn clone(&self) -> Self {
+        (**self).inc_ref();
+        IntrusiveArc { ptr: self.ptr }
+    }
+}
+
+impl<T: RefCounted + ?Sized> Drop for IntrusiveArc<T> {
+    fn drop(&mut self) {
+        unsafe { T::dec_ref(self.ptr) };
+    }
+}
+
+#[test]
+fn test() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // この時点では weak ポインタはアップグレード可能
+    let x = Arc::new(("hello", DetectDrop));
+    let y = Arc::downgrade(&x);
+    let z = Arc::downgrade(&x);
 
     let t = std::thread::spawn(move || {
         // この時点では weak ポインタはアップグレード可能
@@ -207,3 +1477,711 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[test]
+fn test_try_unwrap() {
+    let x = Arc::new(String::from("hello"));
+    let y = x.clone();
+    let w = Arc::downgrade(&x);
+
+    // 他の Arc があるうちは取り出せず、そのまま返ってくる
+    let x = Arc::try_unwrap(x).unwrap_err();
+    drop(y);
+
+    assert_eq!(Arc::try_unwrap(x).ok().unwrap(), "hello");
+    // 値は取り出されたので、weak ポインタはアップグレード不可能
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_into_inner() {
+    let x = Arc::new(String::from("hello"));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let x = x.clone();
+            std::thread::spawn(move || Arc::into_inner(x))
+        })
+        .collect();
+
+    let mut taken: Vec<_> = handles
+        .into_iter()
+        .filter_map(|t| t.join().unwrap())
+        .collect();
+    taken.extend(Arc::into_inner(x));
+
+    // 同時にドロップしても、ちょうど 1 つだけが値を受け取る
+    assert_eq!(taken, ["hello"]);
+}
+
+#[test]
+fn test_make_mut() {
+    let mut x = Arc::new(vec![1, 2, 3]);
+    let ptr = x.ptr;
+
+    // 共有されていなければ、その場で書き換える
+    Arc::make_mut(&mut x).push(4);
+    assert_eq!(x.ptr, ptr);
+
+    // 他の Arc があれば複製してから書き換える
+    let y = x.clone();
+    Arc::make_mut(&mut x).push(5);
+    assert_eq!(*x, [1, 2, 3, 4, 5]);
+    assert_eq!(*y, [1, 2, 3, 4]);
+    drop(y);
+
+    // Weak しか残っていなければ、データをムーブして Weak と切り離す
+    let w = Arc::downgrade(&x);
+    Arc::make_mut(&mut x).push(6);
+    assert_eq!(*x, [1, 2, 3, 4, 5, 6]);
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_unsized() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop(u32);
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    trait Value {
+        fn value(&self) -> u32;
+    }
+
+    impl Value for DetectDrop {
+        fn value(&self) -> u32 {
+            self.0
+        }
+    }
+
+    let s: Arc<str> = Arc::from("hello");
+    let w = Arc::downgrade(&s);
+    assert_eq!(&*w.upgrade().unwrap(), "hello");
+    drop(s);
+    assert!(w.upgrade().is_none());
+
+    let v: Arc<[DetectDrop]> = (1..=3).map(DetectDrop).collect();
+    assert_eq!(v.iter().map(|d| d.0).sum::<u32>(), 6);
+    drop(v);
+    assert_eq!(NUM_DROPS.load(Relaxed), 3);
+
+    let b: Box<dyn Value> = Box::new(DetectDrop(4));
+    let b: Arc<dyn Value> = Arc::from(b);
+    assert_eq!(b.value(), 4);
+    drop(b);
+    assert_eq!(NUM_DROPS.load(Relaxed), 4);
+
+    let x = Arc::new(DetectDrop(5));
+    let y: Arc<dyn Value> = unsize_arc!(x.clone(), dyn Value);
+    drop(x);
+    assert_eq!(y.value(), 5);
+    drop(y);
+    assert_eq!(NUM_DROPS.load(Relaxed), 5);
+}
+
+#[test]
+fn test_new_cyclic() {
+    struct Node {
+        this: Weak<Node>,
+        upgraded_during_construction: bool,
+    }
+
+    let node = Arc::new_cyclic(|weak| Node {
+        this: weak.clone(),
+        upgraded_during_construction: weak.upgrade().is_some(),
+    });
+
+    assert!(!node.upgraded_during_construction);
+    let this = node.this.upgrade().unwrap();
+    assert_eq!(this.ptr, node.ptr);
+}
+
+#[test]
+fn test_raw() {
+    let x: Arc<str> = Arc::from("hello");
+    let w = Arc::downgrade(&x);
+
+    let p = Arc::into_raw(x);
+    unsafe {
+        assert_eq!(&*p, "hello");
+        Arc::increment_strong_count(p);
+        Arc::decrement_strong_count(p);
+    }
+    let x = unsafe { Arc::from_raw(p) };
+    assert_eq!(Arc::as_ptr(&x), p);
+
+    let wp = w.into_raw();
+    assert_eq!(wp, p);
+    let w = unsafe { Weak::from_raw(wp) };
+    assert_eq!(&*w.upgrade().unwrap(), "hello");
+
+    drop(x);
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_counts() {
+    let x = Arc::new(1);
+    let y = x.clone();
+    let w = Arc::downgrade(&x);
+    assert_eq!(Arc::strong_count(&x), 2);
+    assert_eq!(Arc::weak_count(&x), 1);
+    assert_eq!(w.strong_count(), 2);
+    assert_eq!(w.weak_count(), 1);
+
+    assert!(Arc::ptr_eq(&x, &y));
+    assert!(!Arc::ptr_eq(&x, &Arc::new(1)));
+    assert!(w.ptr_eq(&Arc::downgrade(&y)));
+
+    drop(x);
+    drop(y);
+    assert_eq!(w.strong_count(), 0);
+    assert_eq!(w.weak_count(), 0);
+}
+
+#[test]
+fn test_atomic_arc() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop(usize);
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let slot = AtomicArc::new(Arc::new(DetectDrop(0)));
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                // 値は単調に増えていくはず
+                let mut last = 0;
+                for _ in 0..100 {
+                    let x = slot.load();
+                    assert!(x.0 >= last);
+                    last = x.0;
+                }
+            });
+        }
+        for i in 1..=100 {
+            slot.store(Arc::new(DetectDrop(i)));
+        }
+    });
+
+    let current = slot.load();
+    assert_eq!(current.0, 100);
+    assert_eq!(NUM_DROPS.load(Relaxed), 100);
+
+    // 期待した値でなければ差し替えない
+    let prev = slot.compare_and_swap(&Arc::new(DetectDrop(0)), Arc::new(DetectDrop(101)));
+    assert!(Arc::ptr_eq(&prev, &current));
+    assert_eq!(NUM_DROPS.load(Relaxed), 102);
+    drop(prev);
+
+    let prev = slot.compare_and_swap(&current, Arc::new(DetectDrop(102)));
+    assert!(Arc::ptr_eq(&prev, &current));
+    assert_eq!(slot.load().0, 102);
+
+    // 今の値から次の値を作る
+    let old = slot.update(|x| DetectDrop(x.0 + 1));
+    assert_eq!((old.0, slot.load().0), (102, 103));
+
+    drop((old, prev, current, slot));
+    assert_eq!(NUM_DROPS.load(Relaxed), 105);
+}
+
+#[test]
+fn test_weak_new() {
+    let w = Weak::<String>::new();
+    assert!(w.upgrade().is_none());
+    assert_eq!(w.strong_count(), 0);
+    assert_eq!(w.weak_count(), 0);
+
+    let w2 = w.clone();
+    assert!(w.ptr_eq(&w2));
+    let w2 = unsafe { Weak::from_raw(w2.into_raw()) };
+    assert!(w2.upgrade().is_none());
+    assert!(!w.ptr_eq(&Arc::downgrade(&Arc::new(String::new()))));
+}
+
+#[test]
+fn test_new_uninit() {
+    let mut x = Arc::<[u8; 1024]>::new_uninit();
+    Arc::get_mut(&mut x).unwrap().write([7; 1024]);
+    let x = unsafe { x.assume_init() };
+    assert!(x.iter().all(|&b| b == 7));
+
+    let z = unsafe { Arc::<u64>::new_zeroed().assume_init() };
+    assert_eq!(*z, 0);
+
+    let mut v = Arc::<[String]>::new_uninit_slice(3);
+    for (i, s) in Arc::get_mut(&mut v).unwrap().iter_mut().enumerate() {
+        s.write(i.to_string());
+    }
+    let v = unsafe { v.assume_init() };
+    assert_eq!(*v, ["0", "1", "2"]);
+
+    let z = unsafe { Arc::<[u32]>::new_zeroed_slice(4).assume_init() };
+    assert_eq!(*z, [0; 4]);
+}
+
+#[test]
+fn test_unique_arc() {
+    let mut x = UniqueArc::new(Vec::new());
+    let w = UniqueArc::downgrade(&x);
+    x.push(1);
+    x.push(2);
+    // 共有するまではアップグレードできない
+    assert!(w.upgrade().is_none());
+
+    let x = UniqueArc::into_arc(x);
+    assert_eq!(*w.upgrade().unwrap(), [1, 2]);
+    drop(x);
+    assert!(w.upgrade().is_none());
+
+    // 共有しないままドロップしてもよい
+    let x = UniqueArc::new(String::from("hello"));
+    let w = UniqueArc::downgrade(&x);
+    drop(x);
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_traits() {
+    use std::collections::{BTreeSet, HashMap};
+
+    let x: Arc<str> = Arc::from("hello");
+    assert_eq!(format!("{x} {x:?}"), "hello \"hello\"");
+    assert_eq!(format!("{x:p}"), format!("{:p}", Arc::as_ptr(&x)));
+    assert_eq!(format!("{:?}", Arc::downgrade(&x)), "(Weak)");
+
+    // 値が等しければ、別の ArcData でもキーとして一致する
+    let mut map = HashMap::new();
+    map.insert(x.clone(), 1);
+    assert_eq!(map[&Arc::<str>::from("hello")], 1);
+    assert_eq!(map.get("hello"), Some(&1));
+
+    let set: BTreeSet<Arc<i32>> = [3, 1, 2].into_iter().map(Arc::from).collect();
+    assert_eq!(set.into_iter().map(|x| *x).collect::<Vec<_>>(), [1, 2, 3]);
+
+    assert_eq!(*Arc::<Vec<i32>>::default(), []);
+    assert_eq!(&*Arc::<str>::default(), "");
+    assert!(Arc::new(1) < Arc::new(2));
+
+    fn assert_unwind_safe<T: UnwindSafe>() {}
+    assert_unwind_safe::<Arc<i32>>();
+}
+
+#[test]
+fn test_pin() {
+    use std::marker::PhantomPinned;
+
+    // 自分自身のアドレスを覚えておく、移動してはいけない構造体
+    struct SelfRef {
+        this: AtomicPtr<SelfRef>,
+        _pinned: PhantomPinned,
+    }
+
+    let x = Arc::pin(SelfRef {
+        this: AtomicPtr::new(ptr::null_mut()),
+        _pinned: PhantomPinned,
+    });
+    x.this.store(&*x as *const SelfRef as *mut SelfRef, Relaxed);
+
+    let y = x.clone();
+    let handle = thread::spawn(move || ptr::eq(y.this.load(Relaxed), &*y));
+    assert!(handle.join().unwrap());
+    assert_eq!(
+        x.this.load(Relaxed) as *const SelfRef,
+        &*x as *const SelfRef
+    );
+}
+
+#[test]
+fn test_waker() {
+    struct CountWakes(AtomicUsize);
+
+    impl Wake for CountWakes {
+        fn wake(this: Arc<Self>) {
+            this.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    let count = Arc::new(CountWakes(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let waker2 = waker.clone();
+    assert_eq!(Arc::strong_count(&count), 3);
+
+    waker.wake_by_ref();
+    waker.wake();
+    assert_eq!(Arc::strong_count(&count), 2);
+    drop(waker2);
+    assert_eq!(Arc::strong_count(&count), 1);
+    assert_eq!(count.0.load(Relaxed), 2);
+}
+
+#[test]
+fn test_allocator() {
+    struct CountingAlloc {
+        live: AtomicUsize,
+    }
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            self.live.fetch_add(1, Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.fetch_sub(1, Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let alloc = CountingAlloc {
+        live: AtomicUsize::new(0),
+    };
+    let x = Arc::new_in(String::from("hello"), &alloc);
+    let w = Arc::downgrade(&x);
+    // 複製も同じアロケータに確保される
+    let mut y = x.clone();
+    Arc::make_mut(&mut y).push('!');
+    assert_eq!(alloc.live.load(Relaxed), 2);
+    assert_eq!(*y, "hello!");
+
+    drop((x, y));
+    // Weak が残っているので、1 つはまだ解放されない
+    assert_eq!(alloc.live.load(Relaxed), 1);
+    drop(w);
+    assert_eq!(alloc.live.load(Relaxed), 0);
+}
+
+#[test]
+fn test_intrusive_arc() {
+    static NUM_RELEASES: AtomicUsize = AtomicUsize::new(0);
+
+    // C ライブラリのオブジェクトの代わり
+    struct Object {
+        refs: AtomicUsize,
+        value: i32,
+    }
+
+    unsafe impl RefCounted for Object {
+        fn inc_ref(&self) {
+            inc_ref_count(&self.refs);
+        }
+
+        unsafe fn dec_ref(this: NonNull<Self>) {
+            if dec_ref_count(&this.as_ref().refs) {
+                NUM_RELEASES.fetch_add(1, Relaxed);
+                drop(Box::from_raw(this.as_ptr()));
+            }
+        }
+    }
+
+    let raw = Box::into_raw(Box::new(Object {
+        refs: AtomicUsize::new(1),
+        value: 123,
+    }));
+    let x = unsafe { IntrusiveArc::from_raw(raw) };
+    let y = x.clone();
+    assert_eq!(x.refs.load(Relaxed), 2);
+    assert!(IntrusiveArc::ptr_eq(&x, &y));
+
+    let t = thread::spawn(move || y.value);
+    assert_eq!(t.join().unwrap(), 123);
+    assert_eq!(x.refs.load(Relaxed), 1);
+
+    // C 側に参照を渡して、戻ってきたものを引き取る
+    let p = IntrusiveArc::into_raw(x);
+    assert_eq!(NUM_RELEASES.load(Relaxed), 0);
+    let x = unsafe { IntrusiveArc::from_raw(p) };
+    drop(x);
+    assert_eq!(NUM_RELEASES.load(Relaxed), 1);
+
+    // 32 ビットのカウンタと foo_ref/foo_unref を持つ C ライブラリの代わり
+    use std::sync::atomic::AtomicI32;
+
+    struct Handle {
+        refs: AtomicI32,
+    }
+
+    fn handle_ref(h: *const Handle) {
+        unsafe { (*h).refs.fetch_add(1, Relaxed) };
+    }
+
+    fn handle_unref(h: *const Handle) {
+        if unsafe { (*h).refs.fetch_sub(1, AcqRel) } == 1 {
+            NUM_RELEASES.fetch_add(1, Relaxed);
+            drop(unsafe { Box::from_raw(h.cast_mut()) });
+        }
+    }
+
+    unsafe impl RefCounted for Handle {
+        fn inc_ref(&self) {
+            handle_ref(self);
+        }
+
+        unsafe fn dec_ref(this: NonNull<Self>) {
+            handle_unref(this.as_ptr());
+        }
+    }
+
+    let raw = Box::into_raw(Box::new(Handle {
+        refs: AtomicI32::new(1),
+    }));
+    let x = unsafe { IntrusiveArc::from_raw(raw) };
+    let y = x.clone();
+    assert_eq!(x.refs.load(Relaxed), 2);
+    drop((x, y));
+    assert_eq!(NUM_RELEASES.load(Relaxed), 2);
+}
+
+#[cfg(feature = "drop-hooks")]
+#[test]
+fn test_last_drop_hooks() {
+    static EVENTS: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    let x = Arc::new(1);
+    Arc::on_last_drop(&x, || EVENTS.lock().push("first"));
+    let y = x.clone();
+    thread::spawn(move || Arc::on_last_drop(&y, || EVENTS.lock().push("second")))
+        .join()
+        .unwrap();
+    assert!(EVENTS.lock().is_empty());
+    drop(x);
+    assert_eq!(*EVENTS.lock(), ["first", "second"]);
+
+    // 値を取り出したときも一度だけ呼ばれる
+    let x = Arc::new(2);
+    Arc::on_last_drop(&x, || EVENTS.lock().push("unwrap"));
+    assert_eq!(Arc::try_unwrap(x).ok(), Some(2));
+    assert_eq!(EVENTS.lock().len(), 3);
+
+    // make_mut でデータをムーブしたときは、フックも一緒に移る
+    let mut x = Arc::new(3);
+    Arc::on_last_drop(&x, || EVENTS.lock().push("moved"));
+    let w = Arc::downgrade(&x);
+    *Arc::make_mut(&mut x) += 1;
+    drop(w);
+    assert_eq!(EVENTS.lock().len(), 3);
+    drop(x);
+    assert_eq!(EVENTS.lock()[3], "moved");
+}
+
+#[cfg(feature = "drop-hooks")]
+#[test]
+fn test_new_deferred() {
+    static DROPPED_ON: Mutex<Option<String>> = Mutex::new(None);
+
+    struct Large(Vec<u8>);
+
+    impl Drop for Large {
+        fn drop(&mut self) {
+            *DROPPED_ON.lock() = thread::current().name().map(String::from);
+        }
+    }
+
+    let x = Arc::new_deferred(Large(vec![0; 1024]));
+    let w = Arc::downgrade(&x);
+    let y = x.clone();
+    thread::spawn(move || drop(y)).join().unwrap();
+    assert_eq!(x.0.len(), 1024);
+    drop(x);
+    assert!(w.upgrade().is_none());
+    reclaimer::flush();
+    assert_eq!(DROPPED_ON.lock().as_deref(), Some("arc-reclaimer"));
+    assert_eq!(Weak::strong_count(&w), 0);
+
+    // make_mut で複製しても、遅延ドロップの指定は引き継がれる
+    #[derive(Clone)]
+    struct Value(u32);
+
+    let mut x = Arc::new_deferred(Value(1));
+    let y = x.clone();
+    Arc::make_mut(&mut x).0 += 1;
+    assert!(x.data().deferred_reclaim().is_some());
+    assert_eq!((x.0, y.0), (2, 1));
+}
+
+#[cfg(feature = "drop-hooks")]
+#[test]
+fn test_new_deferred_panic() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct PanicOnDrop;
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("drop");
+        }
+    }
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // ドロップがパニックしても、回収スレッドは後の値を回収し続ける
+    drop(Arc::new_deferred(PanicOnDrop));
+    reclaimer::flush();
+    drop(Arc::new_deferred(DetectDrop));
+    reclaimer::flush();
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
+
+#[test]
+fn test_overflow() {
+    let x = Arc::new(1);
+    let w = Arc::downgrade(&x);
+    assert_eq!(*Arc::try_clone(&x).unwrap(), 1);
+    assert_eq!(*w.try_upgrade().unwrap().unwrap(), 1);
+
+    // 上限まで clone したことにする
+    x.data().data_ref_count.store(MAX_REFCOUNT + 1, Relaxed);
+    assert_eq!(Arc::try_clone(&x).err(), Some(OverflowError));
+    assert_eq!(w.try_upgrade().err(), Some(OverflowError));
+    assert_eq!(Arc::strong_count(&x), MAX_REFCOUNT + 1);
+    x.data().data_ref_count.store(1, Relaxed);
+
+    drop(x);
+    assert!(w.try_upgrade().unwrap().is_none());
+}
+
+#[cfg(feature = "overflow-panic")]
+#[test]
+fn test_overflow_panic() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    let x = Arc::new(1);
+    x.data().data_ref_count.store(MAX_REFCOUNT + 1, Relaxed);
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| x.clone())).is_err());
+    // インクリメントは取り消されている
+    assert_eq!(Arc::strong_count(&x), MAX_REFCOUNT + 1);
+    x.data().data_ref_count.store(1, Relaxed);
+
+    // 侵入的な参照カウントも同じ方法で処理する
+    let refs = AtomicUsize::new(MAX_REFCOUNT + 1);
+    assert!(panic::catch_unwind(|| inc_ref_count(&refs)).is_err());
+    assert_eq!(refs.load(Relaxed), MAX_REFCOUNT + 1);
+}
+
+#[cfg(all(feature = "overflow-saturate", not(feature = "overflow-panic")))]
+#[test]
+fn test_overflow_saturate() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new(DetectDrop);
+    x.data().data_ref_count.store(MAX_REFCOUNT + 1, Relaxed);
+    let y = x.clone();
+    assert_eq!(Arc::strong_count(&x), SATURATED);
+
+    // 固定されたカウントは減らないので、データはリークする
+    drop(y);
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+}
+
+#[test]
+fn test_downgrade_get_mut_stress() {
+    // スレッド数をコア数より多くしても、downgrade がスピンし続けてライブロックしないこと
+    let threads = thread::available_parallelism().map_or(4, |n| n.get()) * 4;
+    let iterations = if cfg!(miri) { 20 } else { 20_000 };
+
+    let mut x = Arc::new(0usize);
+    thread::scope(|s| {
+        for i in 0..threads {
+            let mut x = x.clone();
+            s.spawn(move || {
+                for _ in 0..iterations {
+                    if i % 2 == 0 {
+                        // 他のスレッドがクローンを持っているので失敗するが、その間はロックされる
+                        assert!(Arc::get_mut(&mut x).is_none());
+                    } else {
+                        drop(Arc::downgrade(&x));
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(Arc::strong_count(&x), 1);
+    assert_eq!(Arc::weak_count(&x), 0);
+    *Arc::get_mut(&mut x).unwrap() += 1;
+    assert_eq!(*x, 1);
+    let w = Arc::downgrade(&x);
+    assert!(Arc::get_mut(&mut x).is_none());
+    drop(w);
+    assert!(Arc::get_mut(&mut x).is_some());
+}
+
+#[test]
+fn test_downgrade_parked() {
+    // get_mut がロックしたまま止まっている間に downgrade が眠り、ロックが外れたら全員起きること
+    let rounds = if cfg!(miri) { 3 } else { 200 };
+    let x = Arc::new(0usize);
+    for round in 0..rounds {
+        x.data()
+            .alloc_ref_count
+            .compare_exchange(1, usize::MAX, Acquire, Relaxed)
+            .unwrap();
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| drop(Arc::downgrade(&x)));
+            }
+            // スピンし終えて眠るまで待つこともあれば、その途中でロックを外すこともある
+            thread::sleep(std::time::Duration::from_micros(round % 4 * 50));
+            Arc::unlock_alloc_ref_count(&x);
+        });
+    }
+    assert_eq!(Arc::weak_count(&x), 0);
+}
+
+#[test]
+fn test_atomic_arc_reclaim() {
+    use std::sync::atomic::AtomicBool;
+
+    // 読んだ値は、手放すまでドロップされていない
+    struct Value(AtomicBool);
+
+    impl Drop for Value {
+        fn drop(&mut self) {
+            self.0.store(false, Relaxed);
+        }
+    }
+
+    let iterations = if cfg!(miri) { 20 } else { 10_000 };
+    let slot = AtomicArc::new(Arc::new(Value(AtomicBool::new(true))));
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                while !done.load(Relaxed) {
+                    let x = slot.load();
+                    assert!(x.0.load(Relaxed));
+                }
+            });
+        }
+        for _ in 0..iterations {
+            slot.store(Arc::new(Value(AtomicBool::new(true))));
+        }
+        done.store(true, Relaxed);
+    });
+}
@@ -0,0 +1,139 @@
+//! `leak-tracker` フィーチャを有効にすると、確保中の ArcData をすべてグローバルな表に記録する。
+//!
+//! 循環参照などで解放されなかった ArcData を、`live_allocations` や `dump_live_allocations` で調べられる。
+//! バックトレースは `std::backtrace::Backtrace::capture` で取るので、
+//! 環境変数 `RUST_BACKTRACE` (か `RUST_LIB_BACKTRACE`) が設定されているときだけ記録される。
+
+use std::any;
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+
+use crate::lock::Mutex;
+
+struct Record {
+    type_name: &'static str,
+    backtrace: Backtrace,
+}
+
+/// ArcData のアドレスから、その確保の記録への表
+static LIVE: Mutex<BTreeMap<usize, Record>> = Mutex::new(BTreeMap::new());
+
+/// 解放されていない ArcData の情報
+#[derive(Debug)]
+pub struct LiveAllocation {
+    pub address: usize,
+    /// データの型名
+    pub type_name: &'static str,
+    /// 確保したときのバックトレース。記録されていなければ "disabled backtrace" など
+    pub backtrace: String,
+}
+
+/// ArcData を確保したことを記録する
+pub(super) fn register<T: ?Sized>(ptr: *const ()) {
+    let record = Record {
+        type_name: any::type_name::<T>(),
+        backtrace: Backtrace::capture(),
+    };
+    LIVE.lock().insert(ptr.addr(), record);
+}
+
+/// ArcData の中身を別の型として扱うようになったことを記録する。
+/// (`[u8]` から `str` への変換や `assume_init` など)
+pub(super) fn retype<T: ?Sized>(ptr: *const ()) {
+    if let Some(record) = LIVE.lock().get_mut(&ptr.addr()) {
+        record.type_name = any::type_name::<T>();
+    }
+}
+
+/// ArcData を解放したことを記録する。
+/// 解放した後では、同じアドレスに確保された別の ArcData の記録を消してしまうことがあるので、解放する前に呼ぶ。
+pub(super) fn unregister(ptr: *const ()) {
+    LIVE.lock().remove(&ptr.addr());
+}
+
+/// 解放されていない ArcData を、アドレス順に返す
+pub fn live_allocations() -> Vec<LiveAllocation> {
+    LIVE.lock()
+        .iter()
+        .map(|(&address, record)| LiveAllocation {
+            address,
+            type_name: record.type_name,
+            backtrace: record.backtrace.to_string(),
+        })
+        .collect()
+}
+
+/// 解放されていない ArcData を標準エラー出力に書き出し、その数を返す。
+/// プログラムの終了時に呼ぶことを想定している。
+pub fn dump_live_allocations() -> usize {
+    let live = live_allocations();
+    for a in &live {
+        eprintln!("leaked ArcData<{}> at {:#x}", a.type_name, a.address);
+        eprintln!("{}", a.backtrace);
+    }
+    live.len()
+}
+
+#[test]
+fn test() {
+    use super::{Arc, Weak};
+
+    struct Node {
+        next: Mutex<Option<Arc<Node>>>,
+    }
+
+    fn count_nodes() -> usize {
+        live_allocations()
+            .iter()
+            .filter(|a| a.type_name == any::type_name::<Node>())
+            .count()
+    }
+
+    // 循環参照を作ると、両方のノードが残る
+    let a = Arc::new(Node {
+        next: Mutex::new(None),
+    });
+    let b = Arc::new(Node {
+        next: Mutex::new(Some(a.clone())),
+    });
+    *a.next.lock() = Some(b.clone());
+    let w: Weak<Node> = Arc::downgrade(&a);
+    drop((a, b));
+    assert_eq!(count_nodes(), 2);
+
+    // 循環を断ち切れば、どちらも解放される
+    let a = w.upgrade().unwrap();
+    let b = a.next.lock().take().unwrap();
+    drop((a, b));
+    drop(w);
+    assert_eq!(count_nodes(), 0);
+}
+
+#[test]
+fn test_type_name() {
+    use super::Arc;
+
+    struct Marker;
+
+    fn type_name_at(address: usize) -> Option<&'static str> {
+        live_allocations()
+            .into_iter()
+            .find(|a| a.address == address)
+            .map(|a| a.type_name)
+    }
+
+    // 変換した後の型で記録されている
+    let s = Arc::<str>::from("hello");
+    assert_eq!(
+        type_name_at(s.ptr.addr().get()),
+        Some(any::type_name::<str>())
+    );
+    let m = unsafe { Arc::<Marker>::new_uninit().assume_init() };
+    assert_eq!(
+        type_name_at(m.ptr.addr().get()),
+        Some(any::type_name::<Marker>())
+    );
+    let address = m.ptr.addr().get();
+    drop(m);
+    assert_eq!(type_name_at(address), None);
+}
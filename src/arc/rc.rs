@@ -0,0 +1,238 @@
+//! 非アトミックな参照カウントを使う `Rc`。
+//!
+//! `Arc` と同じ ArcData を使うので、共有される前にスレッド内で組み立てておき、
+//! 唯一の参照になったところで `Rc::into_arc` によってそのまま `Arc` に変換できる。
+//! カウンタは AtomicUsize だが、このスレッドからしか触らないので load と store だけで更新する。
+
+use std::alloc::Layout;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+
+use super::{Allocator, Arc, ArcData, Global};
+
+pub struct Rc<T: ?Sized> {
+    ptr: NonNull<ArcData<T>>,
+    /// カウンタをアトミックでない操作で更新するので、Send にも Sync にもしない
+    _not_send: PhantomData<*const ()>,
+}
+
+pub struct Weak<T: ?Sized> {
+    ptr: NonNull<ArcData<T>>,
+    _not_send: PhantomData<*const ()>,
+}
+
+fn increment(count: &AtomicUsize) {
+    let n = count.load(Relaxed);
+    if n > usize::MAX / 2 {
+        std::process::abort();
+    }
+    count.store(n + 1, Relaxed);
+}
+
+/// デクリメント後の値を返す
+fn decrement(count: &AtomicUsize) -> usize {
+    let n = count.load(Relaxed) - 1;
+    count.store(n, Relaxed);
+    n
+}
+
+impl<T> Rc<T> {
+    pub fn new(data: T) -> Rc<T> {
+        let ptr = NonNull::from(Box::leak(Box::new(ArcData {
+            data_ref_count: AtomicUsize::new(1),
+            alloc_ref_count: AtomicUsize::new(1),
+            #[cfg(feature = "drop-hooks")]
+            hooks: Default::default(),
+            alloc: Global,
+            data: UnsafeCell::new(ManuallyDrop::new(data)),
+        })));
+        // into_arc で Arc になりうるので、Arc と同じく記録しておく
+        #[cfg(feature = "leak-tracker")]
+        super::leak_tracker::register::<T>(ptr.as_ptr() as *const ());
+        Rc {
+            ptr,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Rc<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn get_mut(rc: &mut Self) -> Option<&mut T> {
+        // 他のスレッドから触られることはないので、Arc と違ってロックはいらない
+        if rc.data().data_ref_count.load(Relaxed) != 1
+            || rc.data().alloc_ref_count.load(Relaxed) != 1
+        {
+            return None;
+        }
+        unsafe { Some(&mut *rc.data().data.get()) }
+    }
+
+    pub fn downgrade(rc: &Self) -> Weak<T> {
+        increment(&rc.data().alloc_ref_count);
+        Weak {
+            ptr: rc.ptr,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn strong_count(rc: &Self) -> usize {
+        rc.data().data_ref_count.load(Relaxed)
+    }
+
+    pub fn weak_count(rc: &Self) -> usize {
+        rc.data().alloc_ref_count.load(Relaxed) - 1
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    /// 唯一の参照で `Weak` もなければ、ArcData をそのまま `Arc` として返す。
+    /// そうでなければ `rc` をそのまま返す。
+    pub fn into_arc(rc: Self) -> Result<Arc<T>, Self> {
+        // Weak が残っていると、Arc を共有した後にこのスレッドから非アトミックに触られてしまう
+        if rc.data().data_ref_count.load(Relaxed) != 1
+            || rc.data().alloc_ref_count.load(Relaxed) != 1
+        {
+            return Err(rc);
+        }
+        let ptr = rc.ptr;
+        mem::forget(rc);
+        // Arc を他のスレッドに渡すには同期が必要なので、ここまでの書き込みはそのスレッドから見える
+        Ok(Arc { ptr })
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.data().data_ref_count.load(Relaxed)
+    }
+
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        if self.data().data_ref_count.load(Relaxed) == 0 {
+            return None;
+        }
+        increment(&self.data().data_ref_count);
+        Some(Rc {
+            ptr: self.ptr,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+impl<T: ?Sized> Deref for Rc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> Clone for Rc<T> {
+    fn clone(&self) -> Self {
+        increment(&self.data().data_ref_count);
+        Rc {
+            ptr: self.ptr,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        increment(&self.data().alloc_ref_count);
+        Weak {
+            ptr: self.ptr,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for Rc<T> {
+    fn drop(&mut self) {
+        if decrement(&self.data().data_ref_count) == 0 {
+            unsafe {
+                ManuallyDrop::drop(&mut *self.data().data.get());
+            }
+            // すべての `Rc<T>` を代表していた暗黙の weak ポインタをドロップする
+            drop(Weak {
+                ptr: self.ptr,
+                _not_send: PhantomData,
+            });
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if decrement(&self.data().alloc_ref_count) == 0 {
+            unsafe {
+                let layout = Layout::for_value(self.ptr.as_ref());
+                #[cfg(feature = "leak-tracker")]
+                super::leak_tracker::unregister(self.ptr.as_ptr() as *const ());
+                Global.deallocate(self.ptr.cast(), layout);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let mut x = Rc::new(("hello", DetectDrop));
+    assert!(Rc::get_mut(&mut x).is_some());
+    let y = x.clone();
+    let w = Rc::downgrade(&x);
+    assert!(Rc::get_mut(&mut x).is_none());
+    assert_eq!(Rc::strong_count(&x), 2);
+    assert_eq!(Rc::weak_count(&x), 1);
+
+    drop(x);
+    assert_eq!(w.upgrade().unwrap().0, "hello");
+    drop(y);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_into_arc() {
+    let mut x = Rc::new(vec![1, 2, 3]);
+    Rc::get_mut(&mut x).unwrap().push(4);
+
+    // 共有されていれば変換できない
+    let y = x.clone();
+    let x = Rc::into_arc(x).err().unwrap();
+    drop(y);
+    let w = Rc::downgrade(&x);
+    let x = Rc::into_arc(x).err().unwrap();
+    drop(w);
+
+    let x = Rc::into_arc(x).ok().unwrap();
+    let t = std::thread::spawn({
+        let x = x.clone();
+        move || x.iter().sum::<i32>()
+    });
+    assert_eq!(t.join().unwrap(), 10);
+    assert_eq!(Arc::strong_count(&x), 1);
+}
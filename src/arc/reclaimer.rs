@@ -0,0 +1,86 @@
+//! `Arc::new_deferred` で作った値を、バックグラウンドのスレッドでドロップする。
+//!
+//! 回収スレッドは最初に遅延ドロップが要求されたときに起動し、プロセスが終わるまで動き続ける。
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::NonNull;
+use std::sync::Once;
+use std::thread;
+
+use crate::lock::{Condvar, Mutex};
+
+/// 回収スレッドに渡す ArcData と、そのドロップの仕方
+struct Job {
+    ptr: NonNull<()>,
+    reclaim: unsafe fn(NonNull<()>),
+}
+
+// new_deferred は T: Send を要求するので、ArcData を回収スレッドに渡してよい
+unsafe impl Send for Job {}
+
+struct State {
+    queue: VecDeque<Job>,
+    /// これまでに受け付けたジョブの数
+    queued: u64,
+    /// これまでに回収し終えたジョブの数
+    done: u64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    queue: VecDeque::new(),
+    queued: 0,
+    done: 0,
+});
+/// ジョブが追加されたことを回収スレッドに伝える
+static QUEUED: Condvar = Condvar::new();
+/// ジョブが終わったことを `flush` に伝える
+static DONE: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+/// `reclaim(ptr)` を回収スレッドで呼ぶ
+///
+/// # Safety
+///
+/// `reclaim(ptr)` は任意のスレッドから一度だけ呼んでよいものでなければならない。
+pub(super) unsafe fn defer(ptr: NonNull<()>, reclaim: unsafe fn(NonNull<()>)) {
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("arc-reclaimer".into())
+            .spawn(run)
+            .unwrap();
+    });
+    let mut state = STATE.lock();
+    state.queue.push_back(Job { ptr, reclaim });
+    state.queued += 1;
+    drop(state);
+    QUEUED.notify_one();
+}
+
+fn run() {
+    let mut state = STATE.lock();
+    loop {
+        match state.queue.pop_front() {
+            Some(job) => {
+                // ドロップ中に別の値の遅延ドロップが要求されることもあるので、ロックを外しておく
+                drop(state);
+                // 回収スレッドは作り直さないので、値のドロップがパニックしても止めない。
+                // そのときは ArcData を解放できずにリークするが、他のジョブは回収し続ける。
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| unsafe { (job.reclaim)(job.ptr) }));
+                state = STATE.lock();
+                state.done += 1;
+                DONE.notify_all();
+            }
+            None => state = QUEUED.wait(state),
+        }
+    }
+}
+
+/// 呼び出した時点までに遅延された値が、すべてドロップされるまで待つ
+pub fn flush() {
+    let mut state = STATE.lock();
+    let target = state.queued;
+    while state.done < target {
+        state = DONE.wait(state);
+    }
+}
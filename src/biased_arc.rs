@@ -0,0 +1,476 @@
+//! Biased Reference Counting による `Arc`。
+//!
+//! 参照カウントを、所有スレッド (BiasedArc を作ったスレッド) だけが書き込むカウンタと、
+//! それ以外のスレッドが使う共有カウンタに分ける。
+//! 所有スレッドの clone/drop は読み出しと書き込みだけで、fetch_add のような重いアトミック操作を使わないので、
+//! 1 スレッドで使い回すときに速い。
+//!
+//! 2 つのカウンタの和が実際の参照の数になる。所有スレッドで作った参照が他のスレッドでドロップされると、
+//! 共有カウンタは負になる。そのときは所有スレッドのキューに登録しておき、
+//! 所有スレッドが `merge_queued` を呼んだときか、スレッドが終了するときに、
+//! 所有スレッドのカウンタを共有カウンタに足し込む (マージする)。
+//! マージ後はすべての操作が共有カウンタを使う。
+//!
+//! `BiasedWeak::upgrade` は `arc::Weak` と同じく、参照が残っていれば必ず成功し、
+//! 参照がなくなったら None を返す。所有スレッドのカウンタは他のスレッドからも読めるので、
+//! マージ前でもどのスレッドからでも 2 つのカウンタの和を求められる。
+
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicIsize, AtomicUsize, Ordering::*};
+
+use crate::lock::Mutex;
+
+/// shared の下位 2 ビットはフラグで、残りが符号付きのカウント
+const ONE: isize = 4;
+/// 所有スレッドのカウンタが共有カウンタにマージ済み
+const MERGED: isize = 1;
+/// 所有スレッドのキューに登録済み
+const QUEUED: isize = 2;
+
+fn count(shared: isize) -> isize {
+    shared >> 2
+}
+
+struct BiasedArcData<T> {
+    /// 所有スレッドの ID。所有スレッドがなければ 0
+    owner: usize,
+    /// 所有スレッドの参照カウント。書き込むのは所有スレッドだけなので、読み出しと書き込みを分けてよい。
+    /// 他のスレッドも `BiasedWeak::upgrade` で読む。
+    biased_count: AtomicUsize,
+    /// 所有スレッド以外での参照カウント (負になりうる) とフラグ
+    shared: AtomicIsize,
+    /// `BiasedWeak` の数。`BiasedArc` が 1 つでもあればさらに 1 足す
+    alloc_ref_count: AtomicUsize,
+    /// データ本体。weak ポインタしかなくなったらドロップされる
+    data: UnsafeCell<ManuallyDrop<T>>,
+}
+
+pub struct BiasedArc<T> {
+    ptr: NonNull<BiasedArcData<T>>,
+}
+
+// biased_count に書き込むのは所有スレッドだけなので、共有しても問題ない
+unsafe impl<T: Send + Sync> Send for BiasedArc<T> {}
+unsafe impl<T: Send + Sync> Sync for BiasedArc<T> {}
+
+pub struct BiasedWeak<T> {
+    ptr: NonNull<BiasedArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for BiasedWeak<T> {}
+unsafe impl<T: Send + Sync> Sync for BiasedWeak<T> {}
+
+impl<T> BiasedArcData<T> {
+    /// 今のスレッドが所有スレッドのカウンタを使ってよければ true
+    fn is_biased(&self) -> bool {
+        // MERGED を立てるのは所有スレッド自身 (か、所有スレッドの終了後) なので Relaxed でよい
+        current_thread_id() == Some(self.owner) && self.shared.load(Relaxed) & MERGED == 0
+    }
+
+    /// 所有スレッドのカウンタを共有カウンタにマージする。
+    /// 参照がもう残っていなければ、データをドロップする。
+    ///
+    /// # Safety
+    ///
+    /// 所有スレッドか、所有スレッドが終了した後にしか呼べない。
+    unsafe fn merge(ptr: NonNull<Self>) {
+        let data = ptr.as_ref();
+        // マージ後は誰も書き込まない。マージ前の共有カウンタを読んだ upgrade が和を求められるように、ゼロには戻さない。
+        let biased = data.biased_count.load(Relaxed) as isize;
+        // Acquire は他のスレッドの Release デクリメントに対応。
+        // Release は、マージ後に他のスレッドがデータをドロップする場合のため。
+        let old = data.shared.fetch_add(biased * ONE + MERGED, AcqRel);
+        if count(old) + biased == 0 {
+            Self::drop_data(ptr);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// 参照カウントがゼロになっていなければならない。
+    unsafe fn drop_data(ptr: NonNull<Self>) {
+        ManuallyDrop::drop(&mut *ptr.as_ref().data.get());
+        // すべての `BiasedArc` を代表していた暗黙の weak ポインタをドロップする
+        drop(BiasedWeak { ptr });
+    }
+}
+
+impl<T> BiasedArc<T> {
+    pub fn new(data: T) -> BiasedArc<T> {
+        let (owner, biased_count, shared) = match current_thread_id() {
+            Some(id) => (id, 1, 0),
+            // スレッドの終了処理中なら、はじめからマージ済みとして共有カウンタだけを使う
+            None => (0, 0, ONE | MERGED),
+        };
+        BiasedArc {
+            ptr: NonNull::from(Box::leak(Box::new(BiasedArcData {
+                owner,
+                biased_count: AtomicUsize::new(biased_count),
+                shared: AtomicIsize::new(shared),
+                alloc_ref_count: AtomicUsize::new(1),
+                data: UnsafeCell::new(ManuallyDrop::new(data)),
+            }))),
+        }
+    }
+
+    fn data(&self) -> &BiasedArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn downgrade(arc: &Self) -> BiasedWeak<T> {
+        if arc.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        BiasedWeak { ptr: arc.ptr }
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+}
+
+impl<T> BiasedWeak<T> {
+    fn data(&self) -> &BiasedArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// 所有スレッドでも共有カウンタをインクリメントする。
+    /// 和を求めてから共有カウンタを書き換えるまでに、他のスレッドのドロップやマージが割り込まないようにするため。
+    pub fn upgrade(&self) -> Option<BiasedArc<T>> {
+        let data = self.data();
+        // Acquire は他のスレッドの Release デクリメントに対応。
+        // ドロップされた参照を所有スレッドが作ったときの書き込みが、biased_count の読み出しから見える。
+        let mut n = data.shared.load(Acquire);
+        loop {
+            let total = if n & MERGED != 0 {
+                count(n)
+            } else {
+                // 所有スレッドが並行して clone/drop していても、参照が残っている間は和が正のまま。
+                // 和がゼロなら参照はもうないので、所有スレッドがカウンタを書き換えることもない。
+                data.biased_count.load(Relaxed) as isize + count(n)
+            };
+            if total == 0 {
+                // マージ前なら、他のスレッドで最後の参照がドロップされ、マージを待っている
+                return None;
+            }
+            assert!(count(n) < isize::MAX / 8);
+            if let Err(e) = data
+                .shared
+                .compare_exchange_weak(n, n + ONE, Acquire, Acquire)
+            {
+                n = e;
+                continue;
+            }
+            return Some(BiasedArc { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Deref for BiasedArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data().data.get() }
+    }
+}
+
+impl<T> Clone for BiasedArc<T> {
+    fn clone(&self) -> Self {
+        let data = self.data();
+        if data.is_biased() {
+            let n = data.biased_count.load(Relaxed);
+            if n > usize::MAX / 2 {
+                std::process::abort();
+            }
+            data.biased_count.store(n + 1, Relaxed);
+        } else if count(data.shared.fetch_add(ONE, Relaxed)) > isize::MAX / 8 {
+            std::process::abort();
+        }
+        BiasedArc { ptr: self.ptr }
+    }
+}
+
+impl<T> Clone for BiasedWeak<T> {
+    fn clone(&self) -> Self {
+        if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        BiasedWeak { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for BiasedArc<T> {
+    fn drop(&mut self) {
+        let data = self.data();
+        if data.is_biased() {
+            let n = data.biased_count.load(Relaxed) - 1;
+            data.biased_count.store(n, Relaxed);
+            if n == 0 {
+                // 所有スレッドの参照がなくなったので、以降は共有カウンタだけを使う
+                unsafe { BiasedArcData::merge(self.ptr) };
+            }
+            return;
+        }
+        let old = data.shared.fetch_sub(ONE, Release);
+        if old & MERGED != 0 {
+            if count(old) == 1 {
+                fence(Acquire);
+                unsafe { BiasedArcData::drop_data(self.ptr) };
+            }
+        } else if count(old) <= 0 && old & QUEUED == 0 {
+            // 所有スレッドの参照をここでドロップしたので、共有カウンタが負になった。
+            // 和がゼロになっているかもしれないので、所有スレッドにマージを頼む。
+            if data.shared.fetch_or(QUEUED, Relaxed) & QUEUED == 0 {
+                enqueue(self.ptr);
+            }
+        }
+    }
+}
+
+impl<T> Drop for BiasedWeak<T> {
+    fn drop(&mut self) {
+        if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
+            fence(Acquire);
+            unsafe {
+                drop(Box::from_raw(self.ptr.as_ptr()));
+            }
+        }
+    }
+}
+
+/// 所有スレッドにマージを頼んでいる BiasedArcData
+struct Queued {
+    ptr: NonNull<()>,
+    /// マージして、キューが持っていた weak ポインタをドロップする
+    merge: unsafe fn(NonNull<()>),
+}
+
+// キューに入れたものは所有スレッドか、所有スレッドの終了後にしか触らない
+unsafe impl Send for Queued {}
+
+/// スレッド ID ごとのマージ待ちキュー。終了したスレッドのエントリはない。
+static QUEUES: Mutex<BTreeMap<usize, Vec<Queued>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+struct ThreadHandle {
+    id: usize,
+}
+
+impl ThreadHandle {
+    fn new() -> Self {
+        let id = NEXT_THREAD_ID.fetch_add(1, Relaxed);
+        QUEUES.lock().insert(id, Vec::new());
+        ThreadHandle { id }
+    }
+}
+
+impl Drop for ThreadHandle {
+    fn drop(&mut self) {
+        // キューを取り除いた後は、このスレッドが所有する BiasedArc のマージは他のスレッドが行う。
+        // ロックを通して、ここまでの所有スレッドのカウンタへの書き込みがそのスレッドから見える。
+        let queue = QUEUES.lock().remove(&self.id).unwrap_or_default();
+        merge_all(queue);
+    }
+}
+
+thread_local! {
+    static THREAD: ThreadHandle = ThreadHandle::new();
+}
+
+/// 今のスレッドの ID。スレッドの終了処理中なら None
+fn current_thread_id() -> Option<usize> {
+    THREAD.try_with(|t| t.id).ok()
+}
+
+fn enqueue<T>(ptr: NonNull<BiasedArcData<T>>) {
+    unsafe fn merge<T>(ptr: NonNull<()>) {
+        let weak = BiasedWeak {
+            ptr: ptr.cast::<BiasedArcData<T>>(),
+        };
+        // 所有スレッドの参照がなくなったときに、すでにマージ済みかもしれない
+        if weak.data().shared.load(Relaxed) & MERGED == 0 {
+            BiasedArcData::merge(weak.ptr);
+        }
+    }
+
+    let data = unsafe { ptr.as_ref() };
+    // キューにいる間に解放されないように、weak ポインタを 1 つ持っておく
+    data.alloc_ref_count.fetch_add(1, Relaxed);
+    let queued = Queued {
+        ptr: ptr.cast(),
+        merge: merge::<T>,
+    };
+    let mut queues = QUEUES.lock();
+    match queues.get_mut(&data.owner) {
+        Some(queue) => queue.push(queued),
+        None => {
+            // 所有スレッドはもう終了しているので、自分でマージする
+            drop(queues);
+            merge_all(vec![queued]);
+        }
+    }
+}
+
+fn merge_all(queue: Vec<Queued>) {
+    for queued in queue {
+        unsafe { (queued.merge)(queued.ptr) };
+    }
+}
+
+/// 今のスレッドが所有する BiasedArc のうち、マージを待っているものをマージする。
+/// 他のスレッドで最後の参照がドロップされた値は、ここで (またはスレッドの終了時に) ドロップされる。
+pub fn merge_queued() {
+    let Some(id) = current_thread_id() else {
+        return;
+    };
+    let queue = match QUEUES.lock().get_mut(&id) {
+        Some(queue) => std::mem::take(queue),
+        None => return,
+    };
+    merge_all(queue);
+}
+
+#[test]
+fn test() {
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // 所有スレッドの中だけで使う
+    let x = BiasedArc::new(("hello", DetectDrop));
+    let y = x.clone();
+    let w = BiasedArc::downgrade(&x);
+    drop(x);
+    assert_eq!(w.upgrade().unwrap().0, "hello");
+    drop(y);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(w.upgrade().is_none());
+
+    // 他のスレッドと共有する
+    let x = BiasedArc::new(("hello", DetectDrop));
+    let w = BiasedArc::downgrade(&x);
+    let y = x.clone();
+    thread::spawn(move || {
+        let z = y.clone();
+        assert_eq!(z.0, "hello");
+        // 所有スレッドで作った参照をここでドロップすると、共有カウンタは負になる
+        drop(y);
+    })
+    .join()
+    .unwrap();
+
+    // 和はまだ 1 なのでドロップされない
+    merge_queued();
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 2);
+    assert!(w.upgrade().is_none());
+}
+
+#[test]
+fn test_owner_exit() {
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let (x, w) = thread::spawn(|| {
+        let x = BiasedArc::new(DetectDrop);
+        let w = BiasedArc::downgrade(&x);
+        let y = x.clone();
+        drop(x);
+        (y, w)
+    })
+    .join()
+    .unwrap();
+    // 所有スレッドはもう終了しているので、ここでマージされてドロップされる
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(w.upgrade().is_none());
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let x = BiasedArc::new(DetectDrop);
+        let y = x.clone();
+        thread::scope(|s| {
+            s.spawn(|| drop(x));
+        });
+        // 和は 1 のまま、キューに入った状態で終了する
+        tx.send(y).unwrap();
+    })
+    .join()
+    .unwrap();
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    drop(rx.recv().unwrap());
+    assert_eq!(NUM_DROPS.load(Relaxed), 2);
+}
+
+#[test]
+fn test_upgrade_before_merge() {
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // 所有スレッドに参照が残っていれば、共有カウンタが負でも他のスレッドからアップグレードできる
+    let x = BiasedArc::new(DetectDrop);
+    let w = BiasedArc::downgrade(&x);
+    let y = x.clone();
+    thread::spawn(move || drop(y)).join().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let z = w.upgrade().unwrap();
+            drop(z);
+            assert!(w.upgrade().is_some());
+        });
+    });
+    assert!(w.upgrade().is_some());
+    drop(x);
+    merge_queued();
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(w.upgrade().is_none());
+
+    let x = BiasedArc::new(DetectDrop);
+    let w = BiasedArc::downgrade(&x);
+    thread::scope(|s| {
+        s.spawn(|| {
+            // 最後の参照をドロップした。マージ前でも、どのスレッドからもアップグレードできない
+            drop(x);
+            assert!(w.upgrade().is_none());
+        });
+    });
+    assert!(w.upgrade().is_none());
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    merge_queued();
+    assert_eq!(NUM_DROPS.load(Relaxed), 2);
+    thread::scope(|s| {
+        s.spawn(|| assert!(w.upgrade().is_none()));
+    });
+}
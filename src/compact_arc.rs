@@ -0,0 +1,210 @@
+//! 2 つの参照カウントを 1 つの `AtomicU64` に詰めた `Arc`。
+//!
+//! 下位 32 ビットが `CompactArc` の数、上位 32 ビットが `CompactWeak` の数 (`CompactArc` があればさらに 1 足す)。
+//! ヘッダが 8 バイトになるので、小さなオブジェクトを大量に共有するときにメモリを節約できる。
+//! 両方のカウントを一度に読めるので、`get_mut` は `arc::Arc` のようなロックをせずに 1 回のロードで判定できる。
+//!
+//! それぞれのカウントが `u32::MAX / 2` を超えると、プロセスをアボートする。
+//! (`arc::Arc` の `usize::MAX / 2` よりずっと小さいので、注意が必要)
+
+use std::cell::UnsafeCell;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicU64, Ordering::*};
+
+/// 強参照 1 つ分
+const STRONG: u64 = 1;
+/// weak 参照 1 つ分
+const WEAK: u64 = 1 << 32;
+/// それぞれのカウントの上限
+const LIMIT: u64 = u32::MAX as u64 / 2;
+
+fn strong(counts: u64) -> u64 {
+    counts & (WEAK - 1)
+}
+
+fn weak(counts: u64) -> u64 {
+    counts >> 32
+}
+
+struct CompactArcData<T> {
+    /// 下位 32 ビットが強参照の数、上位 32 ビットが weak 参照の数
+    counts: AtomicU64,
+    /// データ本体。weak ポインタしかなくなったらドロップされる
+    data: UnsafeCell<ManuallyDrop<T>>,
+}
+
+pub struct CompactArc<T> {
+    ptr: NonNull<CompactArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for CompactArc<T> {}
+unsafe impl<T: Send + Sync> Sync for CompactArc<T> {}
+
+pub struct CompactWeak<T> {
+    ptr: NonNull<CompactArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for CompactWeak<T> {}
+unsafe impl<T: Send + Sync> Sync for CompactWeak<T> {}
+
+impl<T> CompactArc<T> {
+    pub fn new(data: T) -> CompactArc<T> {
+        CompactArc {
+            ptr: NonNull::from(Box::leak(Box::new(CompactArcData {
+                counts: AtomicU64::new(STRONG + WEAK),
+                data: UnsafeCell::new(ManuallyDrop::new(data)),
+            }))),
+        }
+    }
+
+    fn data(&self) -> &CompactArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+        // 強参照が自分だけで weak もなければ、&mut self を持っている間に増えることはない。
+        // Acquire はドロップ時の Release デクリメントに対応。
+        if arc.data().counts.load(Acquire) != STRONG + WEAK {
+            return None;
+        }
+        unsafe { Some(&mut *arc.data().data.get()) }
+    }
+
+    pub fn downgrade(arc: &Self) -> CompactWeak<T> {
+        if weak(arc.data().counts.fetch_add(WEAK, Relaxed)) > LIMIT {
+            std::process::abort();
+        }
+        CompactWeak { ptr: arc.ptr }
+    }
+
+    pub fn strong_count(arc: &Self) -> usize {
+        strong(arc.data().counts.load(Relaxed)) as usize
+    }
+
+    pub fn weak_count(arc: &Self) -> usize {
+        // 強参照が持っている暗黙の weak ポインタは数えない
+        weak(arc.data().counts.load(Relaxed)) as usize - 1
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+}
+
+impl<T> CompactWeak<T> {
+    fn data(&self) -> &CompactArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn upgrade(&self) -> Option<CompactArc<T>> {
+        let mut n = self.data().counts.load(Relaxed);
+        loop {
+            if strong(n) == 0 {
+                return None;
+            }
+            if strong(n) > LIMIT {
+                std::process::abort();
+            }
+            if let Err(e) =
+                self.data()
+                    .counts
+                    .compare_exchange_weak(n, n + STRONG, Relaxed, Relaxed)
+            {
+                n = e;
+                continue;
+            }
+            return Some(CompactArc { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Deref for CompactArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data().data.get() }
+    }
+}
+
+impl<T> Clone for CompactArc<T> {
+    fn clone(&self) -> Self {
+        if strong(self.data().counts.fetch_add(STRONG, Relaxed)) > LIMIT {
+            std::process::abort();
+        }
+        CompactArc { ptr: self.ptr }
+    }
+}
+
+impl<T> Clone for CompactWeak<T> {
+    fn clone(&self) -> Self {
+        if weak(self.data().counts.fetch_add(WEAK, Relaxed)) > LIMIT {
+            std::process::abort();
+        }
+        CompactWeak { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for CompactArc<T> {
+    fn drop(&mut self) {
+        if strong(self.data().counts.fetch_sub(STRONG, Release)) == 1 {
+            fence(Acquire);
+            unsafe {
+                ManuallyDrop::drop(&mut *self.data().data.get());
+            }
+            // すべての `CompactArc` を代表していた暗黙の weak ポインタをドロップする
+            drop(CompactWeak { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Drop for CompactWeak<T> {
+    fn drop(&mut self) {
+        if self.data().counts.fetch_sub(WEAK, Release) == WEAK {
+            fence(Acquire);
+            unsafe {
+                drop(Box::from_raw(self.ptr.as_ptr()));
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // ヘッダは AtomicU64 1 つ分
+    assert_eq!(std::mem::size_of::<CompactArcData<()>>(), 8);
+
+    let mut x = CompactArc::new(("hello", DetectDrop));
+    assert!(CompactArc::get_mut(&mut x).is_some());
+    let y = CompactArc::downgrade(&x);
+    assert!(CompactArc::get_mut(&mut x).is_none());
+    let z = y.clone();
+    assert_eq!(CompactArc::weak_count(&x), 2);
+
+    let t = thread::spawn(move || {
+        let y = y.upgrade().unwrap();
+        assert_eq!(y.0, "hello");
+    });
+    assert_eq!(x.0, "hello");
+    t.join().unwrap();
+
+    assert_eq!(CompactArc::strong_count(&x), 1);
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(z.upgrade().is_none());
+}
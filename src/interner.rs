@@ -0,0 +1,227 @@
+//! `Arc<str>` を使った文字列のインターナ。
+//!
+//! 同じ文字列には同じ `Arc<str>` を返すので、シンボルの比較やハッシュはポインタだけで済む。
+//! 表は `Weak<str>` しか持たないので、シンボルがすべてドロップされた文字列は解放され、
+//! 表のエントリも後でまとめて取り除かれる。
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::ops::Deref;
+
+use crate::arc::{Arc, Weak};
+use crate::lock::Mutex;
+use crate::prune::PruneSchedule;
+
+/// シャードの数。ロックの競合を減らすために表を分割する
+const NUM_SHARDS: usize = 16;
+/// シャードのエントリがこの数を超えるまでは、死んだエントリを取り除かない
+const MIN_PRUNE_LEN: usize = 64;
+
+/// インターンされた文字列
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+// 同じ文字列は同じ ArcData を指すので、中身を比べる必要はない
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).cast::<u8>().addr().hash(state);
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+pub struct Interner {
+    hasher: RandomState,
+    shards: [Mutex<Shard>; NUM_SHARDS],
+}
+
+struct Shard {
+    /// 文字列のハッシュ値から、その値を持つ文字列への表。
+    /// キーに文字列を持たせると二重に確保することになるので、ハッシュ値で引いて中身を比べる。
+    buckets: HashMap<u64, Vec<Weak<str>>>,
+    /// buckets の中の Weak の数
+    len: usize,
+    prune_schedule: PruneSchedule,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            len: 0,
+            prune_schedule: PruneSchedule::new(MIN_PRUNE_LEN),
+        }
+    }
+
+    fn find(&self, hash: u64, s: &str) -> Option<Symbol> {
+        self.buckets
+            .get(&hash)?
+            .iter()
+            .filter_map(|w| w.upgrade())
+            .find(|a| &**a == s)
+            .map(Symbol)
+    }
+
+    fn prune(&mut self) {
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(|w| w.strong_count() > 0);
+            !bucket.is_empty()
+        });
+        self.len = self.buckets.values().map(Vec::len).sum();
+        self.prune_schedule.pruned(self.len);
+    }
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: std::array::from_fn(|_| Mutex::new(Shard::new())),
+        }
+    }
+
+    fn shard(&self, s: &str) -> (u64, &Mutex<Shard>) {
+        let hash = self.hasher.hash_one(s);
+        // HashMap は下位ビットを使うので、シャードは上位ビットで選ぶ
+        let shard = &self.shards[(hash >> 60) as usize % NUM_SHARDS];
+        (hash, shard)
+    }
+
+    /// `s` のシンボルを返す。まだなければ作って登録する。
+    pub fn intern(&self, s: &str) -> Symbol {
+        let (hash, shard) = self.shard(s);
+        let mut shard = shard.lock();
+        if let Some(symbol) = shard.find(hash, s) {
+            return symbol;
+        }
+        if shard.prune_schedule.is_due(shard.len) {
+            shard.prune();
+        }
+        let symbol = Symbol(Arc::from(s));
+        shard
+            .buckets
+            .entry(hash)
+            .or_default()
+            .push(Arc::downgrade(&symbol.0));
+        shard.len += 1;
+        symbol
+    }
+
+    /// `s` のシンボルが生きていれば返す
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        let (hash, shard) = self.shard(s);
+        shard.lock().find(hash, s)
+    }
+
+    /// 生きているシンボルの数
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock();
+                shard
+                    .buckets
+                    .values()
+                    .flatten()
+                    .filter(|w| w.strong_count() > 0)
+                    .count()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// シンボルがすべてドロップされたエントリを取り除く
+    pub fn prune(&self) {
+        for shard in &self.shards {
+            shard.lock().prune();
+        }
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test() {
+    let interner = Interner::new();
+    let a = interner.intern("hello");
+    let b = interner.intern(&String::from("hello"));
+    let c = interner.intern("world");
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_eq!(a.as_str(), "hello");
+    assert_eq!(interner.len(), 2);
+
+    // シンボルがなくなれば、表からも消える
+    drop((a, b));
+    assert!(interner.get("hello").is_none());
+    assert_eq!(interner.len(), 1);
+    interner.prune();
+    let entries: usize = interner.shards.iter().map(|s| s.lock().len).sum();
+    assert_eq!(entries, 1);
+    assert_eq!(interner.get("world"), Some(c));
+
+    // 使い捨てのシンボルを大量に作っても、表は膨らみ続けない
+    for i in 0..10_000 {
+        interner.intern(&i.to_string());
+    }
+    let entries: usize = interner.shards.iter().map(|s| s.lock().len).sum();
+    assert!(entries < NUM_SHARDS * MIN_PRUNE_LEN * 2);
+}
+
+#[test]
+fn test_concurrent() {
+    use std::thread;
+
+    let interner = Interner::new();
+    let symbols: Vec<Vec<Symbol>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..4)
+            .map(|_| s.spawn(|| (0..100).map(|i| interner.intern(&i.to_string())).collect()))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    for other in &symbols[1..] {
+        assert_eq!(other, &symbols[0]);
+    }
+    assert_eq!(interner.len(), 100);
+}
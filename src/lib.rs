@@ -0,0 +1,11 @@
+pub mod arc;
+pub mod biased_arc;
+pub mod channel;
+pub mod compact_arc;
+pub mod interner;
+pub mod lock;
+pub mod persistent;
+mod prune;
+pub mod rcu;
+pub mod spinlock;
+pub mod weak_value_map;
@@ -1,11 +1,9 @@
 use std::sync::atomic::{AtomicUsize, Ordering::*};
-use std::time::Duration;
 use std::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
     sync::atomic::AtomicU32,
 };
-use std::{thread, u32};
 
 use atomic_wait::{wait, wake_all, wake_one};
 
@@ -46,7 +44,7 @@ impl<T> Mutex<T> {
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
         if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
             // ここに到達したということは、Mutex はすでに state 1 or 2 でロックされている
             lock_contended(&self.state);
@@ -134,8 +132,17 @@ impl Condvar {
     }
 }
 
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[test]
 fn test_condvar() {
+    use std::thread;
+    use std::time::Duration;
+
     let mutex = Mutex::new(0);
     let condvar = Condvar::new();
 
@@ -185,7 +192,7 @@ impl<T> RwLock<T> {
         }
     }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    pub fn read(&self) -> ReadGuard<'_, T> {
         let mut s = self.state.load(Relaxed);
         loop {
             // 偶数ならロック可能
@@ -204,7 +211,7 @@ impl<T> RwLock<T> {
         }
     }
 
-    pub fn write(&self) -> WriteGuard<T> {
+    pub fn write(&self) -> WriteGuard<'_, T> {
         let mut s = self.state.load(Relaxed);
         loop {
             // アンロックされていたらロックを試みる
@@ -1,11 +1,8 @@
 use std::{thread, time::Instant};
 
-use lock::Mutex;
-
-mod arc;
-mod channel;
-mod lock;
-mod spinlock;
+use rust_atomics_and_locks::arc::Arc;
+use rust_atomics_and_locks::biased_arc::BiasedArc;
+use rust_atomics_and_locks::lock::Mutex;
 
 fn main() {
     let m = Mutex::new(0);
@@ -22,4 +19,19 @@ fn main() {
     });
     let duration = start.elapsed();
     println!("locked {} times in {:?}", *m.lock(), duration);
+
+    // 所有スレッドで clone と drop を繰り返す
+    let a = Arc::new(0);
+    let start = Instant::now();
+    for _ in 0..10_000_000 {
+        drop(std::hint::black_box(a.clone()));
+    }
+    println!("Arc: cloned 10000000 times in {:?}", start.elapsed());
+
+    let b = BiasedArc::new(0);
+    let start = Instant::now();
+    for _ in 0..10_000_000 {
+        drop(std::hint::black_box(b.clone()));
+    }
+    println!("BiasedArc: cloned 10000000 times in {:?}", start.elapsed());
 }
@@ -0,0 +1,548 @@
+//! `Arc` でノードを共有する永続コレクション。
+//!
+//! clone はルートの `Arc` を複製するだけなので O(1)。
+//! 変更するときは `Arc::make_mut` を使い、共有されていないノードはその場で書き換え、
+//! 共有されているノードはルートからの経路だけを複製する (経路コピー)。
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+use crate::arc::Arc;
+
+/// 単方向の永続リスト
+pub struct List<T> {
+    head: Option<Arc<ListNode<T>>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+struct ListNode<T> {
+    value: T,
+    next: Option<Arc<ListNode<T>>>,
+}
+
+impl<T> List<T> {
+    pub const fn new() -> Self {
+        Self { head: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_front(&mut self, value: T) {
+        let next = self.head.take();
+        self.head = Some(Arc::new(ListNode { value, next }));
+        self.len += 1;
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.head.as_ref().map(|node| &node.value)
+    }
+
+    pub fn iter(&self) -> ListIter<'_, T> {
+        ListIter {
+            next: self.head.as_deref(),
+        }
+    }
+}
+
+impl<T: Clone> List<T> {
+    /// 先頭のノードが共有されていなければ値をムーブし、共有されていれば複製して返す
+    pub fn pop_front(&mut self) -> Option<T> {
+        let node = self.head.take()?;
+        self.len -= 1;
+        match Arc::try_unwrap(node) {
+            Ok(node) => {
+                self.head = node.next;
+                Some(node.value)
+            }
+            Err(node) => {
+                self.head = node.next.clone();
+                Some(node.value.clone())
+            }
+        }
+    }
+
+    /// `index` 番目までのノードのうち、共有されているものだけを複製する
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        // 範囲外なら、途中のノードを複製してから失敗しないように先に返す
+        if index >= self.len {
+            return None;
+        }
+        let mut link = self.head.as_mut()?;
+        for _ in 0..index {
+            link = Arc::make_mut(link).next.as_mut()?;
+        }
+        Some(&mut Arc::make_mut(link).value)
+    }
+}
+
+impl<T> Clone for List<T> {
+    fn clone(&self) -> Self {
+        Self {
+            head: self.head.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        // 再帰的にドロップすると長いリストでスタックが溢れるので、共有されていないノードをループで外していく
+        let mut head = self.head.take();
+        while let Some(node) = head {
+            match Arc::try_unwrap(node) {
+                Ok(mut node) => head = node.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl<T> FromIterator<T> for List<T> {
+    /// 要素の順番はイテレータと同じになる
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let items: Vec<T> = iter.into_iter().collect();
+        let mut list = List::new();
+        for item in items.into_iter().rev() {
+            list.push_front(item);
+        }
+        list
+    }
+}
+
+pub struct ListIter<'a, T> {
+    next: Option<&'a ListNode<T>>,
+}
+
+impl<'a, T> Iterator for ListIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.next?;
+        self.next = node.next.as_deref();
+        Some(&node.value)
+    }
+}
+
+/// 1 段あたりに使うハッシュ値のビット数
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+/// Hash Array Mapped Trie による永続ハッシュマップ
+pub struct HashMap<K, V, S = RandomState> {
+    root: Arc<HamtNode<K, V>>,
+    len: usize,
+    hasher: S,
+}
+
+#[derive(Clone)]
+enum HamtNode<K, V> {
+    /// bitmap の i ビット目が立っていれば、ハッシュ値のその段の 5 ビットが i のエントリがある。
+    /// children にはビットの立っているエントリだけを順に詰める。
+    Branch {
+        bitmap: u32,
+        children: Vec<Entry<K, V>>,
+    },
+    /// ハッシュ値のすべてのビットが一致したエントリ
+    Collision { hash: u64, entries: Vec<(K, V)> },
+}
+
+#[derive(Clone)]
+enum Entry<K, V> {
+    Leaf(u64, K, V),
+    Node(Arc<HamtNode<K, V>>),
+}
+
+impl<K, V> HamtNode<K, V> {
+    fn empty() -> Self {
+        HamtNode::Branch {
+            bitmap: 0,
+            children: Vec::new(),
+        }
+    }
+
+    /// `shift` 段目のビットと、children 中の位置
+    fn index(bitmap: u32, hash: u64, shift: u32) -> (u32, usize) {
+        let bit = 1 << ((hash >> shift) & MASK);
+        (bit, (bitmap & (bit - 1)).count_ones() as usize)
+    }
+
+    /// 2 つのエントリだけを持つ、`shift` 段目のノードを作る。ハッシュ値がすべて一致すれば Collision になる
+    fn pair(shift: u32, a: (u64, K, V), b: (u64, K, V)) -> Self {
+        if shift >= u64::BITS {
+            return HamtNode::Collision {
+                hash: a.0,
+                entries: vec![(a.1, a.2), (b.1, b.2)],
+            };
+        }
+        let (ia, ib) = ((a.0 >> shift) & MASK, (b.0 >> shift) & MASK);
+        if ia == ib {
+            return HamtNode::Branch {
+                bitmap: 1 << ia,
+                children: vec![Entry::Node(Arc::new(Self::pair(shift + BITS, a, b)))],
+            };
+        }
+        let (a, b) = (Entry::Leaf(a.0, a.1, a.2), Entry::Leaf(b.0, b.1, b.2));
+        HamtNode::Branch {
+            bitmap: (1 << ia) | (1 << ib),
+            children: if ia < ib { vec![a, b] } else { vec![b, a] },
+        }
+    }
+
+    fn get<Q>(&self, hash: u64, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let mut node = self;
+        let mut shift = 0;
+        loop {
+            match node {
+                HamtNode::Branch { bitmap, children } => {
+                    let (bit, i) = Self::index(*bitmap, hash, shift);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    match &children[i] {
+                        Entry::Leaf(h, k, v) => {
+                            return (*h == hash && k.borrow() == key).then_some(v);
+                        }
+                        Entry::Node(child) => {
+                            node = child;
+                            shift += BITS;
+                        }
+                    }
+                }
+                HamtNode::Collision { entries, .. } => {
+                    return entries
+                        .iter()
+                        .find(|(k, _)| k.borrow() == key)
+                        .map(|(_, v)| v);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Clone + Eq, V: Clone> HamtNode<K, V> {
+    fn insert(node: &mut Arc<Self>, shift: u32, hash: u64, key: K, value: V) -> Option<V> {
+        match Arc::make_mut(node) {
+            HamtNode::Branch { bitmap, children } => {
+                let (bit, i) = Self::index(*bitmap, hash, shift);
+                if *bitmap & bit == 0 {
+                    *bitmap |= bit;
+                    children.insert(i, Entry::Leaf(hash, key, value));
+                    return None;
+                }
+                match &mut children[i] {
+                    Entry::Leaf(h, k, v) if *h == hash && *k == key => Some(mem::replace(v, value)),
+                    Entry::Leaf(..) => {
+                        // 同じ位置に別のキーがあるので、1 段下のノードに分ける
+                        let Entry::Leaf(h, k, v) = children.remove(i) else {
+                            unreachable!()
+                        };
+                        let pair = Self::pair(shift + BITS, (h, k, v), (hash, key, value));
+                        children.insert(i, Entry::Node(Arc::new(pair)));
+                        None
+                    }
+                    Entry::Node(child) => Self::insert(child, shift + BITS, hash, key, value),
+                }
+            }
+            HamtNode::Collision { entries, .. } => {
+                if let Some((_, v)) = entries.iter_mut().find(|(k, _)| *k == key) {
+                    return Some(mem::replace(v, value));
+                }
+                entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// キーがあることを確認してから呼ぶ (なければ経路を無駄に複製してしまう)
+    fn remove<Q>(node: &mut Arc<Self>, shift: u32, hash: u64, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        match Arc::make_mut(node) {
+            HamtNode::Branch { bitmap, children } => {
+                let (bit, i) = Self::index(*bitmap, hash, shift);
+                if *bitmap & bit == 0 {
+                    return None;
+                }
+                match &mut children[i] {
+                    Entry::Leaf(h, k, _) if *h == hash && (*k).borrow() == key => {
+                        *bitmap &= !bit;
+                        let Entry::Leaf(_, _, v) = children.remove(i) else {
+                            unreachable!()
+                        };
+                        Some(v)
+                    }
+                    Entry::Leaf(..) => None,
+                    Entry::Node(child) => {
+                        let removed = Self::remove(child, shift + BITS, hash, key);
+                        // エントリが 1 つだけになった子ノードは、親に引き上げる
+                        if let Some(leaf) = Self::take_single_leaf(child) {
+                            children[i] = leaf;
+                        }
+                        removed
+                    }
+                }
+            }
+            HamtNode::Collision { entries, .. } => {
+                let i = entries.iter().position(|(k, _)| k.borrow() == key)?;
+                Some(entries.swap_remove(i).1)
+            }
+        }
+    }
+
+    fn take_single_leaf(node: &mut Arc<Self>) -> Option<Entry<K, V>> {
+        match Arc::make_mut(node) {
+            HamtNode::Branch { children, .. }
+                if children.len() == 1 && matches!(children[0], Entry::Leaf(..)) =>
+            {
+                children.pop()
+            }
+            HamtNode::Collision { hash, entries } if entries.len() == 1 => {
+                let (k, v) = entries.pop().unwrap();
+                Some(Entry::Leaf(*hash, k, v))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<K, V> HashMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            root: Arc::new(HamtNode::empty()),
+            len: 0,
+            hasher,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> HashMapIter<'_, K, V> {
+        let mut iter = HashMapIter {
+            stack: Vec::new(),
+            collision: [].iter(),
+        };
+        iter.push(&self.root);
+        iter
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HashMap<K, V, S> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.root.get(self.hasher.hash_one(key), key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, S: BuildHasher> HashMap<K, V, S> {
+    /// 古い値があれば返す
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let hash = self.hasher.hash_one(&key);
+        let old = HamtNode::insert(&mut self.root, 0, hash, key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        self.root.get(hash, key)?;
+        let removed = HamtNode::remove(&mut self.root, 0, hash, key);
+        self.len -= 1;
+        removed
+    }
+}
+
+impl<K, V, S: Clone> Clone for HashMap<K, V, S> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            len: self.len,
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for HashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct HashMapIter<'a, K, V> {
+    /// たどっている途中の Branch の children
+    stack: Vec<std::slice::Iter<'a, Entry<K, V>>>,
+    /// たどっている途中の Collision の entries
+    collision: std::slice::Iter<'a, (K, V)>,
+}
+
+impl<'a, K, V> HashMapIter<'a, K, V> {
+    fn push(&mut self, node: &'a HamtNode<K, V>) {
+        match node {
+            HamtNode::Branch { children, .. } => self.stack.push(children.iter()),
+            HamtNode::Collision { entries, .. } => self.collision = entries.iter(),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for HashMapIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.collision.next() {
+                return Some((k, v));
+            }
+            match self.stack.last_mut()?.next() {
+                Some(Entry::Leaf(_, k, v)) => return Some((k, v)),
+                Some(Entry::Node(child)) => self.push(child),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_list() {
+    let mut a: List<i32> = (1..=5).collect();
+    let b = a.clone();
+    assert_eq!(a.pop_front(), Some(1));
+    a.push_front(0);
+    *a.get_mut(2).unwrap() = 30;
+    assert_eq!(a.iter().copied().collect::<Vec<_>>(), [0, 2, 30, 4, 5]);
+    assert_eq!(b.iter().copied().collect::<Vec<_>>(), [1, 2, 3, 4, 5]);
+    assert_eq!((a.len(), b.len()), (5, 5));
+
+    // 変更した位置より後ろのノードは共有したまま
+    fn node<T>(list: &List<T>, index: usize) -> *const ListNode<T> {
+        let mut node = list.head.as_ref().unwrap();
+        for _ in 0..index {
+            node = node.next.as_ref().unwrap();
+        }
+        Arc::as_ptr(node)
+    }
+    assert_ne!(node(&a, 2), node(&b, 2));
+    assert_eq!(node(&a, 3), node(&b, 3));
+
+    // 範囲外なら何も複製しない
+    let c = b.clone();
+    let mut d = b.clone();
+    assert!(d.get_mut(5).is_none());
+    assert_eq!(node(&d, 0), node(&c, 0));
+
+    // 共有されていなければ、その場で書き換える
+    let ptr = Arc::as_ptr(a.head.as_ref().unwrap());
+    *a.get_mut(0).unwrap() = -1;
+    assert_eq!(Arc::as_ptr(a.head.as_ref().unwrap()), ptr);
+    assert_eq!(a.front(), Some(&-1));
+
+    // 長いリストでもスタックを溢れさせずにドロップできる
+    let long: List<u32> = (0..100_000).collect();
+    drop(long);
+}
+
+#[test]
+fn test_hash_map() {
+    let mut a = HashMap::new();
+    for i in 0..1000 {
+        assert_eq!(a.insert(i, i * 10), None);
+    }
+    let b = a.clone();
+    assert_eq!(a.insert(7, 0), Some(70));
+    for i in (0..1000).step_by(2) {
+        assert_eq!(a.remove(&i), Some(if i == 7 { 0 } else { i * 10 }));
+    }
+    assert_eq!(a.remove(&0), None);
+
+    assert_eq!((a.len(), b.len()), (500, 1000));
+    assert_eq!(a.get(&7), Some(&0));
+    assert_eq!(b.get(&7), Some(&70));
+    assert!(!a.contains_key(&8) && b.contains_key(&8));
+    let mut keys: Vec<_> = a.iter().map(|(k, _)| *k).collect();
+    keys.sort();
+    assert_eq!(keys, (1..1000).step_by(2).collect::<Vec<_>>());
+    assert_eq!(b.iter().count(), 1000);
+}
+
+#[test]
+fn test_hash_map_collision() {
+    use std::hash::{BuildHasherDefault, Hasher};
+
+    // すべてのキーが同じハッシュ値になる
+    #[derive(Default)]
+    struct ZeroHasher;
+
+    impl Hasher for ZeroHasher {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _: &[u8]) {}
+    }
+
+    let mut map = HashMap::with_hasher(BuildHasherDefault::<ZeroHasher>::default());
+    for s in ["a", "b", "c"] {
+        map.insert(s.to_string(), s.len());
+    }
+    let snapshot = map.clone();
+    assert_eq!(map.remove("b"), Some(1));
+    assert_eq!(map.get("a"), Some(&1));
+    assert_eq!(map.get("b"), None);
+    assert_eq!(snapshot.get("b"), Some(&1));
+
+    assert_eq!(map.remove("a"), Some(1));
+    assert_eq!(map.remove("c"), Some(1));
+    assert!(map.is_empty());
+    assert_eq!(map.iter().count(), 0);
+    assert_eq!(snapshot.iter().count(), 3);
+}
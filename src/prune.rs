@@ -0,0 +1,26 @@
+//! 弱参照を持つ表から、死んだエントリをまとめて取り除くタイミングを決める。
+
+/// エントリ数がしきい値に達したら取り除き、しきい値を生き残ったエントリ数の 2 倍にする。
+/// 取り除く処理はエントリ数に比例するが、次に取り除くまでに同じくらいの数の挿入があるので、
+/// 挿入 1 回あたりのコストは定数になる。
+pub(crate) struct PruneSchedule {
+    /// これより小さい表では取り除かない
+    min: usize,
+    at: usize,
+}
+
+impl PruneSchedule {
+    pub(crate) const fn new(min: usize) -> Self {
+        Self { min, at: min }
+    }
+
+    /// エントリが `len` 個あるときに、取り除くべきなら true
+    pub(crate) fn is_due(&self, len: usize) -> bool {
+        len >= self.at
+    }
+
+    /// 取り除いた後に残ったエントリの数を伝える
+    pub(crate) fn pruned(&mut self, live: usize) {
+        self.at = (live * 2).max(self.min);
+    }
+}
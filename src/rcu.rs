@@ -0,0 +1,81 @@
+//! Read-Copy-Update のセル。
+//!
+//! リーダは `AtomicArc::load` でその時点のスナップショットを受け取るだけなので、ブロックしない。
+//! ライタは古い値から新しい値を作って差し替える。ライタ同士は `AtomicArc` の中で直列化されるので、更新が失われることはない。
+//! 古い値は、それを読んだリーダがすべてスナップショットを手放したときに解放される。
+
+use crate::arc::{Arc, AtomicArc};
+
+pub struct RcuCell<T> {
+    current: AtomicArc<T>,
+}
+
+impl<T> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: AtomicArc::new(Arc::new(value)),
+        }
+    }
+
+    /// 現在の値のスナップショットを返す
+    pub fn read(&self) -> Arc<T> {
+        self.current.load()
+    }
+
+    /// 現在の値から `f` で新しい値を作って差し替え、古い値を返す
+    pub fn update(&self, f: impl FnOnce(&T) -> T) -> Arc<T> {
+        self.current.update(f)
+    }
+
+    /// 値を差し替え、古い値を返す
+    pub fn replace(&self, value: T) -> Arc<T> {
+        self.current.swap(Arc::new(value))
+    }
+}
+
+#[test]
+fn test_update_storm() {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Value(usize);
+
+    impl Drop for Value {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let cell = RcuCell::new(Value(0));
+    let done = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..500 {
+                    drop(cell.update(|v| Value(v.0 + 1)));
+                }
+                done.fetch_add(1, Relaxed);
+            });
+        }
+        for _ in 0..4 {
+            s.spawn(|| {
+                // ライタが終わるまで読み続ける。値は巻き戻らない
+                let mut last = 0;
+                while done.load(Relaxed) < 4 {
+                    let snapshot = cell.read();
+                    assert!(snapshot.0 >= last);
+                    last = snapshot.0;
+                }
+            });
+        }
+    });
+
+    // 更新は 1 つも失われず、古い値はすべて解放されている
+    assert_eq!(cell.read().0, 2000);
+    assert_eq!(NUM_DROPS.load(Relaxed), 2000);
+    assert_eq!(cell.replace(Value(0)).0, 2000);
+    assert_eq!(NUM_DROPS.load(Relaxed), 2001);
+}
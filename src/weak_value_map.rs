@@ -0,0 +1,318 @@
+//! 値を `Weak` で持つマップ。
+//!
+//! 値はマップの外に `Arc` が残っている間だけ取り出せる。同じキーのリソースを共有するためのキャッシュに使う。
+//! 値がドロップされたエントリは、挿入のついでにまとめて取り除く。
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+use crate::arc::{Arc, Weak};
+use crate::lock::{Condvar, Mutex, RwLock};
+use crate::prune::PruneSchedule;
+
+/// エントリがこの数を超えるまでは、死んだエントリを取り除かない
+const MIN_PRUNE_LEN: usize = 16;
+
+pub struct WeakValueMap<K, V> {
+    inner: RwLock<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, Slot<V>>,
+    /// 挿入のついでに、死んだエントリを取り除くタイミング
+    prune_schedule: PruneSchedule,
+}
+
+enum Slot<V> {
+    Ready(Weak<V>),
+    /// `get_or_insert_with` が、マップのロックを外して値を作っている
+    Loading(Arc<Loading<V>>),
+}
+
+struct Loading<V> {
+    state: Mutex<LoadState<V>>,
+    /// 値ができた (か、作るのに失敗した) ことを待っているスレッドに伝える
+    done: Condvar,
+}
+
+enum LoadState<V> {
+    Pending,
+    Done(Weak<V>),
+    /// 値を作る関数がパニックした
+    Failed,
+}
+
+impl<V> Slot<V> {
+    fn upgrade(&self) -> Option<Arc<V>> {
+        match self {
+            Slot::Ready(w) => w.upgrade(),
+            Slot::Loading(loading) => match &*loading.state.lock() {
+                LoadState::Done(w) => w.upgrade(),
+                _ => None,
+            },
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        match self {
+            Slot::Ready(w) => w.strong_count() == 0,
+            Slot::Loading(loading) => match &*loading.state.lock() {
+                LoadState::Pending => false,
+                LoadState::Done(w) => w.strong_count() == 0,
+                LoadState::Failed => true,
+            },
+        }
+    }
+}
+
+impl<V> Loading<V> {
+    fn finish(&self, state: LoadState<V>) {
+        *self.state.lock() = state;
+        self.done.notify_all();
+    }
+
+    /// 値ができるまで待つ。失敗したか、もうドロップされていれば None
+    fn wait(&self) -> Option<Arc<V>> {
+        let mut state = self.state.lock();
+        loop {
+            match &*state {
+                LoadState::Pending => state = self.done.wait(state),
+                LoadState::Done(w) => return w.upgrade(),
+                LoadState::Failed => return None,
+            }
+        }
+    }
+}
+
+/// 値を作る関数がパニックしたときに、待っているスレッドを起こす
+struct FailOnUnwind<'a, V>(&'a Loading<V>);
+
+impl<V> Drop for FailOnUnwind<'_, V> {
+    fn drop(&mut self) {
+        self.0.finish(LoadState::Failed);
+    }
+}
+
+impl<K: Hash + Eq, V> Inner<K, V> {
+    fn insert(&mut self, key: K, slot: Slot<V>) -> Option<Arc<V>> {
+        if self.prune_schedule.is_due(self.entries.len()) {
+            self.prune();
+        }
+        self.entries.insert(key, slot)?.upgrade()
+    }
+
+    fn prune(&mut self) {
+        self.entries.retain(|_, slot| !slot.is_dead());
+        self.prune_schedule.pruned(self.entries.len());
+    }
+}
+
+impl<K: Hash + Eq, V> WeakValueMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            inner: RwLock::new(Inner {
+                entries: HashMap::new(),
+                prune_schedule: PruneSchedule::new(MIN_PRUNE_LEN),
+            }),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.inner.read().entries.get(key)?.upgrade()
+    }
+
+    /// `value` を弱参照として登録し、同じキーに生きている値があればそれを返す
+    pub fn insert(&self, key: K, value: &Arc<V>) -> Option<Arc<V>> {
+        self.inner
+            .write()
+            .insert(key, Slot::Ready(Arc::downgrade(value)))
+    }
+
+    /// 生きている値があればそれを返し、なければ `f` で作って登録する。
+    /// 同じキーで複数のスレッドが同時に呼んでも、`f` が呼ばれるのは 1 回だけで、全員が同じ値を受け取る。
+    /// `f` はマップのロックを外して呼ぶので、その間も他のキーの操作は止まらない。
+    /// ただし `f` の中で同じキーの `get_or_insert_with` を呼ぶと、自分を待ち続けてデッドロックする。
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> Arc<V>
+    where
+        K: Clone,
+    {
+        loop {
+            if let Some(value) = self.get(&key) {
+                return value;
+            }
+            let mut inner = self.inner.write();
+            // リードロックを外してから、他のスレッドが登録したり、作り始めたりしたかもしれない
+            let pending = match inner.entries.get(&key) {
+                Some(slot) => {
+                    if let Some(value) = slot.upgrade() {
+                        return value;
+                    }
+                    match slot {
+                        Slot::Loading(loading)
+                            if matches!(*loading.state.lock(), LoadState::Pending) =>
+                        {
+                            Some(loading.clone())
+                        }
+                        _ => None,
+                    }
+                }
+                None => None,
+            };
+            if let Some(loading) = pending {
+                drop(inner);
+                if let Some(value) = loading.wait() {
+                    return value;
+                }
+                // 作るのに失敗したか、もうドロップされたので、最初からやり直す
+                continue;
+            }
+
+            let loading = Arc::new(Loading {
+                state: Mutex::new(LoadState::Pending),
+                done: Condvar::new(),
+            });
+            inner.insert(key.clone(), Slot::Loading(loading.clone()));
+            drop(inner);
+            let guard = FailOnUnwind(&loading);
+            let value = Arc::new(f());
+            mem::forget(guard);
+            let weak = Arc::downgrade(&value);
+            loading.finish(LoadState::Done(weak.clone()));
+            // get がエントリごとの Mutex を取らずに済むように、Ready に置き換える。
+            // その間に取り除かれたり、別の値に置き換えられたりしていれば、そのままにする。
+            let mut inner = self.inner.write();
+            if let Some(slot) = inner.entries.get_mut(&key) {
+                if matches!(slot, Slot::Loading(l) if Arc::ptr_eq(l, &loading)) {
+                    *slot = Slot::Ready(weak);
+                }
+            }
+            return value;
+        }
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.inner.write().entries.remove(key)?.upgrade()
+    }
+
+    /// 値がドロップされたエントリをすべて取り除く
+    pub fn prune(&self) {
+        self.inner.write().prune();
+    }
+}
+
+impl<K: Hash + Eq, V> Default for WeakValueMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test() {
+    let map = WeakValueMap::new();
+    let a = map.get_or_insert_with("a", || String::from("A"));
+    assert_eq!(*map.get("a").unwrap(), "A");
+    assert!(Arc::ptr_eq(
+        &map.get_or_insert_with("a", || unreachable!()),
+        &a
+    ));
+
+    // 外の Arc がなくなれば取り出せない
+    drop(a);
+    assert!(map.get("a").is_none());
+    assert_eq!(*map.get_or_insert_with("a", || String::from("A2")), "A2");
+
+    let b = Arc::new(String::from("B"));
+    assert!(map.insert("b", &b).is_none());
+    assert!(Arc::ptr_eq(&map.remove("b").unwrap(), &b));
+    assert!(map.get("b").is_none());
+
+    // 死んだエントリは挿入のついでに取り除かれる
+    let map = WeakValueMap::new();
+    let keep: Vec<_> = (0..4)
+        .map(|i| map.get_or_insert_with(format!("keep{i}"), || i))
+        .collect();
+    for i in 0..100 {
+        map.get_or_insert_with(format!("tmp{i}"), || i);
+    }
+    assert!(map.inner.read().entries.len() < 50);
+    map.prune();
+    assert_eq!(map.inner.read().entries.len(), keep.len());
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::thread;
+
+    let map = WeakValueMap::new();
+    let calls = AtomicUsize::new(0);
+
+    let values: Vec<Arc<u32>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    map.get_or_insert_with("key", || {
+                        calls.fetch_add(1, Relaxed);
+                        42
+                    })
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    assert_eq!(calls.load(Relaxed), 1);
+    assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+}
+
+#[test]
+fn test_slow_constructor() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let map = WeakValueMap::new();
+    let (started_tx, started_rx) = mpsc::channel();
+    let (finish_tx, finish_rx) = mpsc::channel::<()>();
+    let other = map.get_or_insert_with("other", || 1);
+
+    let map = &map;
+    thread::scope(|s| {
+        let slow = s.spawn(move || {
+            map.get_or_insert_with("slow", || {
+                started_tx.send(()).unwrap();
+                finish_rx.recv().unwrap();
+                2
+            })
+        });
+        started_rx.recv().unwrap();
+        // 値を作っている間も、他のキーは読み書きできる
+        assert!(Arc::ptr_eq(&map.get("other").unwrap(), &other));
+        assert_eq!(*map.get_or_insert_with("another", || 3), 3);
+        assert!(map.get("slow").is_none());
+        let waiter = s.spawn(|| map.get_or_insert_with("slow", || unreachable!()));
+        finish_tx.send(()).unwrap();
+        let slow = slow.join().unwrap();
+        assert!(Arc::ptr_eq(&waiter.join().unwrap(), &slow));
+        assert!(Arc::ptr_eq(&map.get("slow").unwrap(), &slow));
+        // 作り終えたエントリは Ready に置き換わっている
+        assert!(matches!(map.inner.read().entries["slow"], Slot::Ready(_)));
+    });
+
+    // 作るのに失敗したら、次の呼び出しで作り直す
+    let result = thread::scope(|s| {
+        s.spawn(|| map.get_or_insert_with("panic", || panic!()))
+            .join()
+    });
+    assert!(result.is_err());
+    assert_eq!(*map.get_or_insert_with("panic", || 4), 4);
+}